@@ -0,0 +1,46 @@
+//! Micro-benchmarks timed with the timestamp counter.
+//!
+//! Benchmarks are collected like tests: a test binary sets
+//! `#![test_runner(kleinos::bench::bench_runner)]` and marks each benchmark
+//! function with `#[test_case]`.
+
+use crate::{cpu::rdtsc, qemu, serial_println};
+
+/// Iterations run by `bench_runner` for each benchmark
+pub const BENCH_ITERATIONS: u64 = 10_000;
+
+/// Runs `f` `iters` times and prints the average cycles per iteration over
+/// serial.
+///
+/// The numbers include the loop overhead and are only comparable on the
+/// same machine, as the TSC frequency differs between CPUs.
+pub fn bench(name: &str, iters: u64, f: impl Fn()) {
+    let start = rdtsc();
+    for _ in 0..iters {
+        f();
+    }
+    let cycles = rdtsc() - start;
+
+    serial_println!("{}: {} cycles/iter", name, cycles / iters.max(1));
+}
+
+pub trait Benchable {
+    fn run(&self);
+}
+
+impl<T> Benchable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        bench(core::any::type_name::<T>(), BENCH_ITERATIONS, self);
+    }
+}
+
+pub fn bench_runner(benches: &[&dyn Benchable]) {
+    serial_println!("Running {} benchmarks", benches.len());
+    for bench in benches {
+        bench.run();
+    }
+    qemu::qemu_exit(qemu::QemuExitCode::Success);
+}
@@ -0,0 +1,339 @@
+//! Fixed-capacity collections that work without a heap.
+
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A first-in first-out queue holding at most `N` elements.
+///
+/// The queue is lock-free for one producer and one consumer, e.g. an
+/// interrupt handler pushing and the main loop popping. `try_push` and
+/// `try_pop` take `&self` so the queue can live in a plain `static`, which
+/// makes them unsafe: the caller has to uphold the single producer and single
+/// consumer rule. With exclusive access `push` and `pop` are safe.
+pub struct RingBuffer<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    // Both indices run over 0..2 * N, so a full buffer (tail - head == N)
+    // differs from an empty one (tail == head) without wasting a slot.
+    // Index of the oldest element, only written by the consumer
+    head: AtomicUsize,
+    // Index of the next free slot, only written by the producer
+    tail: AtomicUsize,
+}
+
+// SAFETY: Elements are moved from the producer to the consumer, which may run
+// on different threads when T: Send. Access to each slot is handed over
+// through the Release/Acquire ordering on head and tail.
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    const fn advance(index: usize) -> usize {
+        (index + 1) % (2 * N)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (tail + 2 * N - head) % (2 * N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Appends `value`, handing it back if the buffer is full.
+    ///
+    /// # Safety
+    ///
+    /// Must not run concurrently with another `try_push` on the same buffer,
+    /// including from an interrupt handler interrupting a `try_push`.
+    pub unsafe fn try_push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if (tail + 2 * N - head) % (2 * N) == N {
+            return Err(value);
+        }
+
+        // SAFETY: The slot at tail is free as the buffer is not full, the
+        // consumer does not touch it until tail is published below and the
+        // caller guarantees there is no other producer.
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(Self::advance(tail), Ordering::Release);
+        Ok(())
+    }
+
+    /// Removes and returns the oldest element.
+    ///
+    /// # Safety
+    ///
+    /// Must not run concurrently with another `try_pop` on the same buffer,
+    /// including from an interrupt handler interrupting a `try_pop`.
+    pub unsafe fn try_pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // SAFETY: The slot at head was initialized by the producer before it
+        // published tail, the producer does not reuse it until head is
+        // advanced below and the caller guarantees there is no other
+        // consumer.
+        let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+        self.head.store(Self::advance(head), Ordering::Release);
+        Some(value)
+    }
+
+    /// Appends `value`, handing it back if the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        // SAFETY: &mut self rules out any other producer.
+        unsafe { self.try_push(value) }
+    }
+
+    /// Removes and returns the oldest element.
+    pub fn pop(&mut self) -> Option<T> {
+        // SAFETY: &mut self rules out any other consumer.
+        unsafe { self.try_pop() }
+    }
+}
+
+impl<T, const N: usize> Drop for RingBuffer<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> core::fmt::Debug for RingBuffer<T, N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RingBuffer")
+            .field("len", &self.len())
+            .field("capacity", &N)
+            .finish()
+    }
+}
+
+/// A vector holding at most `N` elements inline.
+pub struct ArrayVec<T, const N: usize> {
+    // The first `len` slots are initialized
+    slots: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> ArrayVec<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { MaybeUninit::uninit() }; N],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends `value`, handing it back if the vector is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+
+        self.slots[self.len].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes and returns the last element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        self.len -= 1;
+        // SAFETY: The slot at the old last index was initialized and is no
+        // longer counted in `len`, so it is read out exactly once.
+        Some(unsafe { self.slots[self.len].assume_init_read() })
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        // SAFETY: The first `len` slots are initialized and MaybeUninit<T>
+        // has the same layout as T.
+        unsafe { core::slice::from_raw_parts(self.slots.as_ptr().cast(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // SAFETY: The first `len` slots are initialized and MaybeUninit<T>
+        // has the same layout as T.
+        unsafe { core::slice::from_raw_parts_mut(self.slots.as_mut_ptr().cast(), self.len) }
+    }
+
+    /// Drops all elements.
+    pub fn clear(&mut self) {
+        let elements: *mut [T] = self.as_mut_slice();
+        // Reset first so a panicking destructor cannot cause a double drop.
+        self.len = 0;
+        // SAFETY: The elements were initialized and are no longer reachable
+        // through `len`.
+        unsafe { core::ptr::drop_in_place(elements) };
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T, const N: usize> Default for ArrayVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> core::ops::Deref for ArrayVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> core::ops::DerefMut for ArrayVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: core::fmt::Debug, const N: usize> core::fmt::Debug for ArrayVec<T, N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_ring_buffer_wraps() {
+        let mut buffer: RingBuffer<u8, 3> = RingBuffer::new();
+        assert!(buffer.is_empty());
+
+        for round in 0..5 {
+            assert_eq!(buffer.push(round), Ok(()));
+            assert_eq!(buffer.push(round + 1), Ok(()));
+            assert_eq!(buffer.pop(), Some(round));
+            assert_eq!(buffer.pop(), Some(round + 1));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test_case]
+    fn test_ring_buffer_full() {
+        let mut buffer: RingBuffer<u8, 2> = RingBuffer::new();
+        assert_eq!(buffer.push(1), Ok(()));
+        assert_eq!(buffer.push(2), Ok(()));
+        assert!(buffer.is_full());
+        assert_eq!(buffer.push(3), Err(3));
+        assert_eq!(buffer.pop(), Some(1));
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test_case]
+    fn test_ring_buffer_interleaved() {
+        static BUFFER: RingBuffer<u32, 4> = RingBuffer::new();
+
+        let mut next_push = 0;
+        let mut next_pop = 0;
+        // Push runs of varying length and drain them partially, so the
+        // indices wrap around several times.
+        for round in 0..20 {
+            for _ in 0..round % 4 + 1 {
+                // SAFETY: This test is the only producer.
+                if unsafe { BUFFER.try_push(next_push) }.is_ok() {
+                    next_push += 1;
+                }
+            }
+            for _ in 0..round % 3 + 1 {
+                // SAFETY: This test is the only consumer.
+                if let Some(value) = unsafe { BUFFER.try_pop() } {
+                    assert_eq!(value, next_pop);
+                    next_pop += 1;
+                }
+            }
+            assert_eq!(BUFFER.len(), (next_push - next_pop) as usize);
+        }
+        // SAFETY: This test is the only consumer.
+        while let Some(value) = unsafe { BUFFER.try_pop() } {
+            assert_eq!(value, next_pop);
+            next_pop += 1;
+        }
+        assert_eq!(next_pop, next_push);
+    }
+
+    #[test_case]
+    fn test_array_vec_fill() {
+        let mut vec: ArrayVec<u8, 3> = ArrayVec::new();
+        for i in 0..3 {
+            assert_eq!(vec.push(i), Ok(()));
+        }
+        assert!(vec.is_full());
+        assert_eq!(vec.push(3), Err(3));
+        assert_eq!(vec.as_slice(), &[0, 1, 2]);
+        assert_eq!(vec.pop(), Some(2));
+        assert_eq!(vec.len(), 2);
+        vec.clear();
+        assert!(vec.is_empty());
+        assert_eq!(vec.pop(), None);
+    }
+
+    #[test_case]
+    fn test_array_vec_drops_initialized() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted;
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        {
+            let mut vec: ArrayVec<Counted, 4> = ArrayVec::new();
+            for _ in 0..3 {
+                assert!(vec.push(Counted).is_ok());
+            }
+            drop(vec.pop());
+            assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+        }
+        assert_eq!(DROPS.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,78 @@
+//! CPU identification via the `cpuid` instruction, the timestamp counter and
+//! the hardware random number generator.
+
+pub use core::arch::x86_64::{__cpuid as cpuid, __cpuid_count as cpuid_count, CpuidResult};
+
+/// Returns the vendor string, e.g. `GenuineIntel` or `AuthenticAMD`.
+pub fn cpu_vendor() -> [u8; 12] {
+    // Leaf 0 returns the vendor string in ebx, edx, ecx order
+    let CpuidResult { ebx, ecx, edx, .. } = cpuid(0);
+
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&ecx.to_le_bytes());
+    vendor
+}
+
+/// Reads the timestamp counter, the number of cycles since reset.
+pub fn rdtsc() -> u64 {
+    // SAFETY: rdtsc has no side effects and is available on every x86_64
+    // CPU. It is not serializing, which only affects the precision.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Attempts of `rdrand64` before giving up, as recommended by Intel
+const RDRAND_RETRIES: usize = 10;
+
+/// Returns whether the CPU has the `rdrand` instruction, Intel since Ivy
+/// Bridge and AMD since Zen.
+pub fn has_rdrand() -> bool {
+    cpuid(1).ecx & (1 << 30) != 0
+}
+
+/// Returns a random number from the CPU's hardware generator.
+///
+/// Returns `None` if the CPU has no `rdrand` instruction or the generator
+/// stayed out of entropy for a few attempts, so callers need a fallback.
+pub fn rdrand64() -> Option<u64> {
+    if !has_rdrand() {
+        return None;
+    }
+    for _ in 0..RDRAND_RETRIES {
+        let mut value = 0;
+        // SAFETY: The CPU supports rdrand, checked above. The carry flag,
+        // returned as 1, signals that value holds a random number.
+        if unsafe { core::arch::x86_64::_rdrand64_step(&mut value) } == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_cpu_vendor_is_printable() {
+        assert!(cpu_vendor().iter().all(u8::is_ascii_graphic));
+    }
+
+    #[test_case]
+    fn test_rdtsc_advances() {
+        let start = rdtsc();
+        assert!(rdtsc() > start);
+    }
+
+    #[test_case]
+    fn test_rdrand64() {
+        if !has_rdrand() {
+            assert_eq!(rdrand64(), None);
+            return;
+        }
+        let first = rdrand64().expect("rdrand failed");
+        let second = rdrand64().expect("rdrand failed");
+        assert_ne!(first, second);
+    }
+}
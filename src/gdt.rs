@@ -10,21 +10,34 @@ use x86_64::{
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+
+/// Size of each interrupt stack
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+#[allow(dead_code)]
+#[repr(align(16))]
+struct Stack([u8; IST_STACK_SIZE]);
+
+// Only used by the CPU through the TSS
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+static mut PAGE_FAULT_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+static mut NMI_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+
+/// Returns the initial stack pointer of `stack`, stacks grow downwards.
+fn stack_end(stack: *const Stack) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + IST_STACK_SIZE as u64
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-
-            #[allow(dead_code)]
-            #[repr(align(16))]
-            struct Stack([u8; STACK_SIZE]);
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            stack_start + STACK_SIZE as u64
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            stack_end(&raw const DOUBLE_FAULT_STACK);
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+            stack_end(&raw const PAGE_FAULT_STACK);
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack_end(&raw const NMI_STACK);
         tss
     };
 }
@@ -34,11 +47,17 @@ lazy_static! {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        // Data before code, the order sysret expects. The selectors carry
+        // RPL 3 from the descriptors' privilege level.
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
         (
             gdt,
             Selectors {
                 code_selector,
                 tss_selector,
+                user_code_selector,
+                user_data_selector,
             },
         )
     };
@@ -47,6 +66,13 @@ lazy_static! {
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+}
+
+/// Returns the ring 3 code and data segment selectors.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
 }
 
 pub fn init() {
@@ -56,9 +82,23 @@ pub fn init() {
         CS::set_reg(GDT.1.code_selector);
     }
 
-    // SAFETY: The TSS constructed for the double-fault stack is valid
-    // and the stack allocated and available.
+    // SAFETY: The TSS constructed with the interrupt stacks is valid and
+    // the stacks are allocated and available.
     unsafe {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::PrivilegeLevel;
+
+    #[test_case]
+    fn test_user_selectors_ring3() {
+        let (code, data) = user_selectors();
+        assert_eq!(code.rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(data.rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(code.index(), data.index() + 1);
+    }
+}
@@ -1,8 +1,17 @@
-use crate::{gdt, hlt_loop, print, println};
+use crate::{
+    gdt, halt, memory, println, serial::SERIAL1, serial_println, sync::IrqMutex, vga::SCREEN,
+};
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = 32 + 8;
@@ -12,14 +21,74 @@ pub const PIC_2_OFFSET: u8 = 32 + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
+    Serial1 = PIC_1_OFFSET + 4,
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
     }
+
+    /// IRQ line of the interrupt on the chained PICs.
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+/// Number of IRQ lines of the chained PICs
+pub const IRQ_COUNT: usize = 16;
+/// IRQ of the primary PIC the secondary PIC is connected to
+const CASCADE_IRQ: u8 = 2;
+/// Lowest priority IRQ of each PIC, also raised for spurious interrupts
+const SPURIOUS_PRIMARY_IRQ: u8 = 7;
+const SPURIOUS_SECONDARY_IRQ: u8 = 15;
+
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_2_COMMAND_PORT: u16 = 0xa0;
+/// OCW3 selecting the in-service register for the next command port read
+const PIC_READ_ISR: u8 = 0x0b;
+
+/// Input clock of the programmable interval timer (PIT) in Hz
+pub const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+/// Timer interrupt rate set up by `init`
+pub const DEFAULT_TIMER_FREQUENCY: u32 = 100;
+
+const PIT_CHANNEL0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+// Channel 0, low byte then high byte, mode 3 (square wave), binary counting
+const PIT_CHANNEL0_SQUARE_WAVE: u8 = 0x36;
+
+/// Current PIT divisor, the power-on default 0 counts as 65536
+static PIT_DIVISOR: AtomicU32 = AtomicU32::new(1 << 16);
+
+/// Set once the PIT has been programmed by `init_timer`
+static TIMER_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// The frequency needs a divisor outside of the 16 bit PIT counter
+    UnsupportedFrequency,
 }
 
+/// Device handler run for an IRQ, see `register_irq`
+pub type IrqHandler = fn();
+
+/// Tick count at which the watchdog handler runs, 0 while disarmed
+static WATCHDOG_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Handler run by the timer interrupt when the watchdog expires
+static WATCHDOG_HANDLER: IrqMutex<Option<IrqHandler>> = IrqMutex::new(None);
+
+/// Interrupts received per IRQ since boot
+static IRQ_COUNTS: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+
+/// Device handlers called by the IRQ trampolines, indexed by IRQ
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
 // SAFETY: PICs are chained and at contiguous offsets starting at
 // PIC_1_OFFSET. We access through ring 0 and only via this static protected by
 // a Mutex, hence, no race can occur.
@@ -30,7 +99,22 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        // SAFETY: Each stack index has its own stack in the TSS, used by no
+        // other handler. NMIs can arrive at any point and page faults also
+        // come from an overflowed kernel stack, so neither may rely on it.
+        // A nested fault reuses the stack from its top, page_fault_handler
+        // detects that and never returns to the overwritten frame.
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
+        set_exception_handlers(&mut idt);
         // SAFETY: The stack index matches the stack we set up for the
         // double fault handler in order to _not_ use the default
         // kernel stack which might be overflowed etc.
@@ -39,8 +123,9 @@ lazy_static! {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX)
         };
-        idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+        for (irq, trampoline) in IRQ_TRAMPOLINES.into_iter().enumerate() {
+            idt[PIC_1_OFFSET + irq as u8].set_handler_fn(trampoline);
+        }
         idt
     };
 }
@@ -51,86 +136,575 @@ pub fn init() {
     // SAFETY: The chained PICS are created at the correct offsets and
     // we are running in ring 0 and, hence, access is safe.
     unsafe { PICS.lock().initialize() };
+
+    register_irq(InterruptIndex::Timer.irq(), timer_tick);
+    init_timer(DEFAULT_TIMER_FREQUENCY).expect("default timer frequency is supported");
+}
+
+fn timer_tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let deadline = WATCHDOG_DEADLINE.load(Ordering::Relaxed);
+    if deadline != 0 && ticks >= deadline {
+        WATCHDOG_DEADLINE.store(0, Ordering::Relaxed);
+        let handler = *WATCHDOG_HANDLER.lock();
+        if let Some(handler) = handler {
+            handler();
+        }
+    }
+}
+
+/// Runs `on_timeout` from the timer interrupt once `timeout_ticks` timer
+/// ticks have passed, unless `disarm_watchdog` is called before.
+///
+/// Code spinning with interrupts disabled, e.g. while holding an `IrqMutex`,
+/// blocks the timer and is not caught.
+pub fn arm_watchdog(timeout_ticks: u64, on_timeout: IrqHandler) {
+    *WATCHDOG_HANDLER.lock() = Some(on_timeout);
+    WATCHDOG_DEADLINE.store(ticks() + timeout_ticks.max(1), Ordering::Relaxed);
+}
+
+pub fn disarm_watchdog() {
+    WATCHDOG_DEADLINE.store(0, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since boot in milliseconds.
+///
+/// Assumes all ticks happened at the current timer frequency.
+pub fn uptime_ms() -> u64 {
+    ticks_to_us(ticks()) / 1000
+}
+
+/// Converts a number of timer ticks at the current frequency to
+/// microseconds, rounded down.
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    let divisor = PIT_DIVISOR.load(Ordering::Relaxed);
+    (u128::from(ticks) * u128::from(divisor) * 1_000_000 / u128::from(PIT_BASE_FREQUENCY)) as u64
+}
+
+/// Programs the PIT to raise the timer interrupt `hz` times per second.
+///
+/// The PIT can only divide its base clock by 1 to 65535, so `hz` must lie
+/// between 19 and 1193182. The actual rate is `timer_frequency`.
+pub fn init_timer(hz: u32) -> Result<(), TimerError> {
+    let divisor = match PIT_BASE_FREQUENCY.checked_div(hz) {
+        Some(divisor @ 1..=0xffff) => divisor,
+        _ => return Err(TimerError::UnsupportedFrequency),
+    };
+    let [low, high, ..] = divisor.to_le_bytes();
+
+    let mut command = Port::new(PIT_COMMAND_PORT);
+    let mut channel0 = Port::new(PIT_CHANNEL0_PORT);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // SAFETY: 0x43 and 0x40 are the PIT command and channel 0 ports, we
+        // run in ring 0. Channel 0 only drives the timer IRQ. Interrupts are
+        // disabled so the three writes cannot be interleaved.
+        unsafe {
+            command.write(PIT_CHANNEL0_SQUARE_WAVE);
+            channel0.write(low);
+            channel0.write(high);
+        }
+        PIT_DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+    TIMER_INITIALIZED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns whether timer ticks are currently being counted.
+pub fn timer_running() -> bool {
+    TIMER_INITIALIZED.load(Ordering::Relaxed) && x86_64::instructions::interrupts::are_enabled()
+}
+
+/// Returns the timer interrupt rate in Hz, rounded down.
+pub fn timer_frequency() -> u32 {
+    PIT_BASE_FREQUENCY / PIT_DIVISOR.load(Ordering::Relaxed)
+}
+
+/// Calls `handler` whenever `irq` fires and unmasks `irq` on the PICs.
+///
+/// The end of interrupt is sent after `handler` returns. Registering again
+/// replaces the previous handler.
+pub fn register_irq(irq: u8, handler: IrqHandler) {
+    assert!((irq as usize) < IRQ_COUNT, "IRQ {} out of range", irq);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize] = Some(handler);
+    });
+    unmask_irq(irq);
+}
+
+/// Sets the interrupt masks of both PICs, a set bit disables the IRQ.
+///
+/// Bit n of `primary` masks IRQ n and bit n of `secondary` masks IRQ 8 + n.
+pub fn set_irq_mask(primary: u8, secondary: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // SAFETY: The PICs are configured to the correct ports and we run in
+        // ring 0. Every IRQ vector has a trampoline in the IDT, so any
+        // unmasked IRQ is handled.
+        unsafe { PICS.lock().write_masks(primary, secondary) };
+    });
+}
+
+/// Updates the PIC masks with `f` in a single read-modify-write.
+fn update_irq_mask(f: impl FnOnce([u8; 2]) -> [u8; 2]) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        // SAFETY: See set_irq_mask. Interrupts are disabled and the PICs
+        // locked, so the masks cannot change in between.
+        unsafe {
+            let [primary, secondary] = f(pics.read_masks());
+            pics.write_masks(primary, secondary);
+        }
+    });
+}
+
+/// Stops `irq` from raising interrupts.
+pub fn mask_irq(irq: u8) {
+    assert!((irq as usize) < IRQ_COUNT, "IRQ {} out of range", irq);
+
+    update_irq_mask(|[primary, secondary]| {
+        if irq < 8 {
+            [primary | 1 << irq, secondary]
+        } else {
+            [primary, secondary | 1 << (irq - 8)]
+        }
+    });
+}
+
+/// Lets `irq` raise interrupts again.
+pub fn unmask_irq(irq: u8) {
+    assert!((irq as usize) < IRQ_COUNT, "IRQ {} out of range", irq);
+
+    update_irq_mask(|[primary, secondary]| {
+        if irq < 8 {
+            [primary & !(1 << irq), secondary]
+        } else {
+            // IRQs of the secondary PIC arrive through the cascade IRQ 2
+            [primary & !(1 << CASCADE_IRQ), secondary & !(1 << (irq - 8))]
+        }
+    });
+}
+
+/// Returns the number of interrupts received per IRQ since boot.
+pub fn stats() -> [u64; IRQ_COUNT] {
+    core::array::from_fn(|irq| IRQ_COUNTS[irq].load(Ordering::Relaxed))
+}
+
+/// Prints the interrupt counts of all IRQs that fired over serial.
+pub fn print_interrupt_stats() {
+    for (irq, count) in stats().into_iter().enumerate() {
+        if count > 0 {
+            serial_println!("IRQ {:2}: {}", irq, count);
+        }
+    }
+}
+
+/// Returns whether `irq` is in service on its PIC, i.e. really raised.
+///
+/// A PIC reports its lowest priority IRQ when a request goes away before
+/// the CPU acknowledges it, without setting the in-service bit.
+fn in_service(irq: u8) -> bool {
+    let (port, bit) = if irq < 8 {
+        (PIC_1_COMMAND_PORT, irq)
+    } else {
+        (PIC_2_COMMAND_PORT, irq - 8)
+    };
+    let mut command: Port<u8> = Port::new(port);
+
+    let _pics = PICS.lock();
+    // SAFETY: The port is a PIC command port and we run in ring 0. OCW3 only
+    // selects which register the next read returns. Holding the PICS lock
+    // keeps other PIC accesses from interleaving.
+    let isr = unsafe {
+        command.write(PIC_READ_ISR);
+        command.read()
+    };
+    isr & 1 << bit != 0
+}
+
+/// Runs the handler registered for `irq` and acknowledges the interrupt.
+///
+/// Spurious IRQs 7 and 15 are dropped. Neither PIC expects an end of
+/// interrupt for them, except the primary one for the cascade IRQ that
+/// forwarded a spurious IRQ 15.
+fn dispatch_irq(irq: u8) {
+    if matches!(irq, SPURIOUS_PRIMARY_IRQ | SPURIOUS_SECONDARY_IRQ) && !in_service(irq) {
+        if irq == SPURIOUS_SECONDARY_IRQ {
+            // SAFETY: See below. An interrupt number of the primary PIC only
+            // acknowledges the primary PIC.
+            unsafe {
+                PICS.lock()
+                    .notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ);
+            }
+        }
+        return;
+    }
+
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+
+    // Copy the handler out so it runs without holding the lock
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+
+    // SAFETY: the PICS are configured during initialization to the correct
+    // ports. We run in ring 0 and the access is protected via the Mutex to
+    // ensure no races.
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
+}
+
+/// Defines one interrupt handler per IRQ that forwards to `dispatch_irq`.
+macro_rules! irq_trampolines {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        const IRQ_TRAMPOLINES: [HandlerFunc; IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_trampolines! {
+    0 => irq0_handler,
+    1 => irq1_handler,
+    2 => irq2_handler,
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    7 => irq7_handler,
+    8 => irq8_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler,
+    14 => irq14_handler,
+    15 => irq15_handler,
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/// Debug exceptions from single-stepping and data breakpoints are traps, so
+/// execution continues after the instruction that raised them.
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+/// Spins an unmaskable handler waits for COM1 before giving up
+const UNMASKABLE_LOCK_SPINS: usize = 1_000_000;
+
+/// Prints over serial from handlers that run even while interrupts are
+/// disabled. The interrupted code may hold COM1, so this gives up instead of
+/// deadlocking. The VGA screen is not used, as it may be wedged the same way.
+fn unmaskable_println(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(mut port) = SERIAL1.try_lock_for(UNMASKABLE_LOCK_SPINS) {
+        let _ = port.write_fmt(args);
+        let _ = port.write_str("\n");
+    }
+}
+
+/// Prints a fault report over serial first, then to the screen if it can be
+/// locked in time. Either lock may be held by the faulting code, so neither
+/// is waited for forever and a wedged screen does not keep the report from
+/// reaching serial.
+fn fault_println(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    unmaskable_println(args);
+    if let Some(mut screen) = SCREEN.try_lock_for(UNMASKABLE_LOCK_SPINS) {
+        let _ = screen.write_fmt(args);
+        let _ = screen.write_str("\n");
+        screen.flush();
+    }
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    unmaskable_println(format_args!(
+        "EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}",
+        stack_frame
+    ));
+
+    halt();
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    unmaskable_println(format_args!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame));
+
+    halt();
+}
+
+/// Set while `page_fault_handler` runs, see there
+static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::Cr2;
+    // The handler runs on its own IST stack, so a page fault inside it
+    // starts again at the top of that stack and overwrites the frame of the
+    // first fault. Page faults are never resumed, so the overwritten frame is
+    // not returned to, but its details are lost: only report the nesting.
+    if IN_PAGE_FAULT.swap(true, Ordering::Relaxed) {
+        unmaskable_println(format_args!(
+            "EXCEPTION: PAGE FAULT in the page fault handler\nAccessed Address: {:?}",
+            Cr2::read()
+        ));
+        halt();
+    }
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Addresss: {:?}", Cr2::read());
-    println!("Error code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    let address = Cr2::read();
+    fault_println(format_args!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}",
+        address
+    ));
+    // An overflow of the kernel stack lands here, not in the double fault
+    // handler, since this handler has a stack of its own
+    let stack_overflow = address.is_ok_and(memory::is_stack_guard);
+    if stack_overflow {
+        fault_println(format_args!("Kernel stack overflow"));
+    }
+    fault_println(format_args!(
+        "Instruction Pointer: {:?}\nError code: {:?}",
+        stack_frame.instruction_pointer, error_code
+    ));
+    fault_println(format_args!(
+        "  present: {}, write: {}, user: {}, reserved bit: {}, instruction fetch: {}",
+        error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+        error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+        error_code.contains(PageFaultErrorCode::USER_MODE),
+        error_code.contains(PageFaultErrorCode::MALFORMED_TABLE),
+        error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+    ));
+    fault_println(format_args!("{:#?}", stack_frame));
+    if stack_overflow {
+        dump_stack(stack_frame.stack_pointer);
+    }
 
-    hlt_loop();
+    halt();
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
-    println!(
+    fault_println(format_args!(
         "EXCEPTION: DOUBLE FAULT\nError code: {}\n{:#?}",
         error_code, stack_frame
-    );
+    ));
+    if Cr2::read().is_ok_and(memory::is_stack_guard) {
+        fault_println(format_args!("Kernel stack overflow"));
+    }
+    dump_stack(stack_frame.stack_pointer);
 
-    hlt_loop();
+    halt();
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // SAFETY: the PICS are configured during initialization to the correct
-    // ports. We run in ring 0 and the access is protected via the Mutex to
-    // ensure no races.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// Words printed by `dump_stack`
+const STACK_DUMP_WORDS: u64 = 8;
+
+/// Prints the words at the top of an interrupted stack over serial.
+///
+/// After a stack overflow the stack pointer lies in the unmapped guard page
+/// and reading it would fault again. The last page fault address (CR2) is on
+/// that same page then, so the dump starts at the next page instead, the
+/// deepest frames still on the stack. The dump stops at the end of the page
+/// it starts on, as the stack may end there. The handler runs on its own IST
+/// stack.
+fn dump_stack(stack_pointer: VirtAddr) {
+    const PAGE_SIZE: u64 = 4096;
+
+    let mut start = stack_pointer.align_down(8u64);
+    if let Ok(fault_address) = Cr2::read()
+        && fault_address.align_down(PAGE_SIZE) == stack_pointer.align_down(PAGE_SIZE)
+    {
+        start = stack_pointer.align_up(PAGE_SIZE);
+    }
+    let page_end = start.align_down(PAGE_SIZE) + PAGE_SIZE;
+
+    unmaskable_println(format_args!("Stack from {:?}:", start));
+    for i in 0..STACK_DUMP_WORDS {
+        let address = start + i * 8;
+        if address >= page_end {
+            break;
+        }
+        // SAFETY: The address is aligned and on the page of the interrupted
+        // stack pointer, which is mapped unless it is the guard page skipped
+        // above.
+        let word = unsafe { read_volatile(address.as_ptr::<u64>()) };
+        unmaskable_println(format_args!("  {:#018x}: {:#018x}", address.as_u64(), word));
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
+/// Generates handlers for the faults that are only reported: each prints the
+/// exception name, the error code if the CPU pushes one, and the stack frame
+/// with `fault_println`, then halts. `set_exception_handlers` installs all of
+/// them.
+macro_rules! define_handlers {
+    (
+        without_error_code { $($field:ident => $name:ident, $label:literal;)* }
+        with_error_code { $($err_field:ident => $err_name:ident, $err_label:literal;)* }
+    ) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+                fault_println(format_args!("EXCEPTION: {}\n{:#?}", $label, stack_frame));
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore
-            ));
-    }
+                halt();
+            }
+        )*
 
-    {
-        let mut keyboard = KEYBOARD.lock();
-        let mut port = Port::new(0x60);
-
-        // SAFETY: The port is configured above and we are running in ring 0
-        // ensuring access. Reading the keyboard port has the only sideeffect of
-        // allowing the irq again on EOI.
-        let scancode: u8 = unsafe { port.read() };
-
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
-            && let Some(key) = keyboard.process_keyevent(key_event)
-        {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+        $(
+            extern "x86-interrupt" fn $err_name(stack_frame: InterruptStackFrame, error_code: u64) {
+                fault_println(format_args!(
+                    "EXCEPTION: {}\nError code: {}\n{:#?}",
+                    $err_label, error_code, stack_frame
+                ));
+
+                halt();
             }
+        )*
+
+        fn set_exception_handlers(idt: &mut InterruptDescriptorTable) {
+            $(idt.$field.set_handler_fn($name);)*
+            $(idt.$err_field.set_handler_fn($err_name);)*
         }
+    };
+}
+
+define_handlers! {
+    without_error_code {
+        divide_error => divide_error_handler, "DIVIDE ERROR";
+        overflow => overflow_handler, "OVERFLOW";
+        bound_range_exceeded => bound_range_exceeded_handler, "BOUND RANGE EXCEEDED";
+        invalid_opcode => invalid_opcode_handler, "INVALID OPCODE";
+        device_not_available => device_not_available_handler, "DEVICE NOT AVAILABLE";
+        x87_floating_point => x87_floating_point_handler, "X87 FLOATING POINT";
+        simd_floating_point => simd_floating_point_handler, "SIMD FLOATING POINT";
+        virtualization => virtualization_handler, "VIRTUALIZATION";
+        hv_injection_exception => hv_injection_handler, "HYPERVISOR INJECTION";
+    }
+    with_error_code {
+        invalid_tss => invalid_tss_handler, "INVALID TSS";
+        segment_not_present => segment_not_present_handler, "SEGMENT NOT PRESENT";
+        stack_segment_fault => stack_segment_fault_handler, "STACK SEGMENT FAULT";
+        general_protection_fault => general_protection_fault_handler, "GENERAL PROTECTION FAULT";
+        alignment_check => alignment_check_handler, "ALIGNMENT CHECK";
+        cp_protection_exception => cp_protection_handler, "CONTROL PROTECTION";
+        vmm_communication_exception => vmm_communication_handler, "VMM COMMUNICATION";
+        security_exception => security_exception_handler, "SECURITY EXCEPTION";
     }
+}
 
-    // SAFETY: the PICS are configured during initialization to the correct
-    // ports. We run in ring 0 and the access is protected via the Mutex to
-    // ensure no races.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_init_timer_rejects_unsupported_frequencies() {
+        assert_eq!(init_timer(0), Err(TimerError::UnsupportedFrequency));
+        assert_eq!(init_timer(18), Err(TimerError::UnsupportedFrequency));
+        assert_eq!(
+            init_timer(PIT_BASE_FREQUENCY + 1),
+            Err(TimerError::UnsupportedFrequency)
+        );
+        assert_eq!(timer_frequency(), DEFAULT_TIMER_FREQUENCY);
+    }
+
+    fn irq_masks() -> [u8; 2] {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            // SAFETY: Reading the masks has no side effects, the PICs are
+            // locked with interrupts disabled.
+            unsafe { PICS.lock().read_masks() }
+        })
+    }
+
+    #[test_case]
+    fn test_mask_and_unmask_irq() {
+        // Neither IRQ has a device attached in QEMU
+        mask_irq(5);
+        mask_irq(13);
+        assert_eq!(irq_masks()[0] & 1 << 5, 1 << 5);
+        assert_eq!(irq_masks()[1] & 1 << 5, 1 << 5);
+
+        unmask_irq(13);
+        assert_eq!(irq_masks()[1] & 1 << 5, 0);
+        assert_eq!(irq_masks()[0] & 1 << CASCADE_IRQ, 0);
+        mask_irq(13);
+    }
+
+    #[test_case]
+    fn test_ticks_advance() {
+        let start = ticks();
+        while ticks() == start {
+            x86_64::instructions::hlt();
+        }
+        assert!(uptime_ms() > 0);
+    }
+
+    #[test_case]
+    fn test_watchdog_fires() {
+        static FIRED: AtomicBool = AtomicBool::new(false);
+
+        fn on_timeout() {
+            FIRED.store(true, Ordering::Relaxed);
+        }
+
+        // Borrow the watchdog from the test runner and hand it back after
+        let runner_deadline = WATCHDOG_DEADLINE.load(Ordering::Relaxed);
+        let runner_handler = *WATCHDOG_HANDLER.lock();
+
+        arm_watchdog(1, on_timeout);
+        let start = ticks();
+        while ticks() < start + 2 {
+            x86_64::instructions::hlt();
+        }
+        assert!(FIRED.load(Ordering::Relaxed));
+
+        // The deadline fired and is 0, so the handler is set first
+        *WATCHDOG_HANDLER.lock() = runner_handler;
+        WATCHDOG_DEADLINE.store(runner_deadline, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_timer_irq_counted() {
+        let timer = InterruptIndex::Timer.irq() as usize;
+        let start = stats()[timer];
+        let tick = ticks();
+        while ticks() == tick {
+            x86_64::instructions::hlt();
+        }
+        assert!(stats()[timer] > start);
+    }
+
+    #[test_case]
+    fn test_spurious_irqs_dropped() {
+        assert!(!in_service(SPURIOUS_PRIMARY_IRQ));
+        assert!(!in_service(SPURIOUS_SECONDARY_IRQ));
+
+        // Raised by software, neither IRQ is in service on its PIC
+        let before = stats();
+        // SAFETY: Vectors 39 and 47 are the IRQ 7 and 15 trampolines. For a
+        // spurious IRQ they only read the PIC state, plus an end of interrupt
+        // to the primary PIC for IRQ 15, a no-op with nothing in service.
+        unsafe {
+            core::arch::asm!("int 39", "int 47");
+        }
+        let after = stats();
+        assert_eq!(after[7], before[7]);
+        assert_eq!(after[15], before[15]);
     }
 }
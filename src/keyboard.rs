@@ -0,0 +1,59 @@
+//! PS/2 keyboard input decoded from scancode set 1.
+
+use crate::{
+    collections::RingBuffer,
+    interrupts::{InterruptIndex, register_irq},
+};
+use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub const CHAR_BUFFER_SIZE: usize = 64;
+
+const DATA_PORT: u16 = 0x60;
+
+static KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(Keyboard::new(
+    ScancodeSet1::new(),
+    layouts::Us104Key,
+    HandleControl::Ignore,
+));
+
+/// Typed chars waiting for `pop_char`
+static CHAR_BUFFER: RingBuffer<char, CHAR_BUFFER_SIZE> = RingBuffer::new();
+
+/// Starts collecting typed chars from the keyboard IRQ.
+pub fn init() {
+    register_irq(InterruptIndex::Keyboard.irq(), handle_interrupt);
+}
+
+/// Reads and decodes the pending scancode.
+///
+/// Called for the keyboard IRQ. Chars that do not fit into the
+/// buffer and keys without a char (e.g. arrows) are dropped.
+fn handle_interrupt() {
+    let mut port = Port::new(DATA_PORT);
+
+    // SAFETY: 0x60 is the PS/2 controller data port and we are running in
+    // ring 0. Reading it has the only side effect of allowing the irq again
+    // on EOI.
+    let scancode: u8 = unsafe { port.read() };
+
+    let mut keyboard = KEYBOARD.lock();
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
+        && let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event)
+    {
+        // SAFETY: The keyboard IRQ handler is the only producer and does not
+        // nest, since interrupts are disabled while it runs.
+        let _ = unsafe { CHAR_BUFFER.try_push(character) };
+    }
+}
+
+/// Returns the oldest typed char, if any.
+///
+/// Intended for the main loop. Must not be called from interrupt handlers,
+/// since the char buffer supports only a single consumer.
+pub fn pop_char() -> Option<char> {
+    // SAFETY: Outside of interrupt handlers kleinos runs a single thread, so
+    // there is no concurrent consumer.
+    unsafe { CHAR_BUFFER.try_pop() }
+}
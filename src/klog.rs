@@ -0,0 +1,140 @@
+//! Kernel log output written to the VGA screen and COM1 at once.
+//!
+//! Besides the `klog!`/`klogln!` macros this module provides a `log::Log`
+//! implementation, so drivers can use `log::info!` and friends after `init`.
+
+use crate::{
+    serial::{SERIAL1, SerialPort},
+    sync::IrqMutexGuard,
+    vga::{Color, ColorCode, DEFAULT_COLOR, SCREEN, VgaScreen},
+};
+use core::fmt::Write;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+static LOGGER: KernelLogger = KernelLogger;
+
+/// Writes everything to both the VGA screen and the serial port.
+///
+/// Holds both locks, so output of concurrent writers cannot interleave
+/// differently on the two devices. ANSI color sequences are rendered by the
+/// VGA writer and passed through to the serial terminal as is.
+pub struct Logger<'a> {
+    screen: IrqMutexGuard<'a, VgaScreen>,
+    serial: IrqMutexGuard<'a, SerialPort>,
+}
+
+impl Logger<'_> {
+    /// Locks both outputs, always SCREEN before SERIAL1.
+    pub fn lock() -> Self {
+        Self {
+            screen: SCREEN.lock(),
+            serial: SERIAL1.lock(),
+        }
+    }
+}
+
+impl Write for Logger<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.screen.write_str(s)?;
+        self.serial.write_str(s)
+    }
+}
+
+impl Drop for Logger<'_> {
+    fn drop(&mut self) {
+        self.screen.flush();
+    }
+}
+
+/// `log::Log` backend writing records through [`Logger`].
+///
+/// Errors are shown in red and warnings in yellow on the VGA screen, the
+/// serial output stays plain.
+pub struct KernelLogger;
+
+impl KernelLogger {
+    fn color(level: Level) -> ColorCode {
+        match level {
+            Level::Error => ColorCode::new(Color::Red, Color::Black),
+            Level::Warn => ColorCode::new(Color::Yellow, Color::Black),
+            _ => DEFAULT_COLOR,
+        }
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut logger = Logger::lock();
+        let Logger { screen, serial } = &mut logger;
+        let level = record.level();
+        screen
+            .with_color(Self::color(level), |screen| {
+                writeln!(screen, "[{:<5}] {}", level, record.args())
+            })
+            .expect("log write failed");
+        writeln!(serial, "[{:<5}] {}", level, record.args()).expect("log write failed");
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs [`KernelLogger`] as the `log` backend, showing records up to
+/// `level`.
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[macro_export]
+macro_rules! klog {
+    ($($arg:tt)*) => ($crate::klog::_log(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! klogln {
+    () => ($crate::klog!("\n"));
+    ($($arg:tt)*) => ($crate::klog!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _log(args: core::fmt::Arguments) {
+    Logger::lock().write_fmt(args).expect("log write failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vga::SCREEN;
+
+    #[test_case]
+    fn test_klogln_reaches_screen() {
+        let s = "test_klogln output";
+        klogln!("{}", s);
+        assert!(SCREEN.lock().row_text(1).starts_with(s.as_bytes()));
+    }
+
+    #[test_case]
+    fn test_log_error_is_red() {
+        let _ = super::init(log::LevelFilter::Info);
+        log::error!("test_log_error output");
+
+        let screen = SCREEN.lock();
+        assert!(
+            screen
+                .row_text(1)
+                .starts_with(b"[ERROR] test_log_error output")
+        );
+        assert_eq!(
+            screen.read(1, 0).color,
+            super::KernelLogger::color(log::Level::Error)
+        );
+    }
+}
@@ -8,17 +8,38 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 
+extern crate alloc;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use sync::IrqMutex;
+
+pub mod bench;
+pub mod collections;
+pub mod cpu;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
+pub mod klog;
+pub mod memory;
+pub mod mouse;
+pub mod pci;
 pub mod qemu;
+pub mod sched;
 pub mod serial;
+pub mod sync;
+pub mod time;
+pub mod util;
 pub mod vga;
 
 pub fn init() {
+    memory::heap::init();
     gdt::init();
     interrupts::init();
+    keyboard::init();
+    serial::init_receive();
 
     x86_64::instructions::interrupts::enable();
+    time::calibrate_tsc();
 }
 
 pub fn busy_spin(iterations: usize) {
@@ -27,7 +48,34 @@ pub fn busy_spin(iterations: usize) {
     }
 }
 
-pub fn hlt_loop() -> ! {
+/// Called by `idle_loop` after every wake up
+static IDLE_HOOK: IrqMutex<Option<fn()>> = IrqMutex::new(None);
+
+/// Installs `hook` to run each time `idle_loop` wakes up, e.g. to schedule
+/// work queued by interrupt handlers.
+pub fn set_idle_hook(hook: fn()) {
+    *IDLE_HOOK.lock() = Some(hook);
+}
+
+/// Waits for interrupts forever, running the idle hook after each one.
+///
+/// Enables interrupts, so this is for waiting, not for stopping the kernel.
+pub fn idle_loop() -> ! {
+    x86_64::instructions::interrupts::enable();
+    loop {
+        x86_64::instructions::hlt();
+        let hook = *IDLE_HOOK.lock();
+        if let Some(hook) = hook {
+            hook();
+        }
+    }
+}
+
+/// Stops the CPU for good: disables interrupts and halts.
+///
+/// Only non-maskable interrupts can wake the CPU, after which it halts again.
+pub fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
     loop {
         x86_64::instructions::hlt();
     }
@@ -43,12 +91,26 @@ where
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>());
+        interrupts::arm_watchdog(TEST_TIMEOUT_TICKS, test_timeout);
         self();
+        interrupts::disarm_watchdog();
         serial_println!("[ok]");
     }
 }
 
+/// Timer ticks a test may run before it is considered hung, 10 s at the
+/// default timer frequency. Only enforced once the timer is running.
+const TEST_TIMEOUT_TICKS: u64 = 1000;
+
+fn test_timeout() {
+    serial_println!("[timeout]");
+    qemu::qemu_exit(qemu::QemuExitCode::Failure);
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
+    if !qemu::debug_exit_present() {
+        serial_println!("Warning: isa-debug-exit not found, QEMU will not exit after the tests");
+    }
     serial_println!("Running {} tests", tests.len());
     for test in tests {
         test.run();
@@ -56,11 +118,30 @@ pub fn test_runner(tests: &[&dyn Testable]) {
     qemu::qemu_exit(qemu::QemuExitCode::Success);
 }
 
+/// Set while `expect_panic` runs code that should panic
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` and passes the test run if it panics, fails it otherwise.
+///
+/// A panic cannot be caught, so `test_panic_handler` ends the whole run with
+/// success instead. Tests after the panicking one do not run, which makes
+/// this only useful as the last test of a test binary.
+pub fn expect_panic(f: impl FnOnce()) {
+    EXPECT_PANIC.store(true, Ordering::Relaxed);
+    f();
+    EXPECT_PANIC.store(false, Ordering::Relaxed);
+    panic!("test did not panic");
+}
+
 pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
     use crate::qemu::{QemuExitCode, qemu_exit};
     use core::fmt::Write;
 
     let mut port = crate::serial::SERIAL1.lock();
+    if EXPECT_PANIC.load(Ordering::Relaxed) {
+        writeln!(port, "[ok]").ok();
+        qemu_exit(QemuExitCode::Success);
+    }
     writeln!(port, "[failed]").ok();
     writeln!(port, "Error: {}", info).ok();
     qemu_exit(QemuExitCode::Failure);
@@ -73,7 +154,7 @@ bootloader::entry_point!(lib_test_kernel_main);
 fn lib_test_kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
     use crate::qemu::qemu_exit;
 
-    serial::SERIAL1.lock().init();
+    serial::init();
     init();
     test_main();
     qemu_exit(crate::qemu::QemuExitCode::Success);
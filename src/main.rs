@@ -4,21 +4,89 @@
 #![warn(clippy::undocumented_unsafe_blocks)]
 #![warn(unsafe_op_in_unsafe_fn)]
 
-use kleinos::{hlt_loop, println};
+use kleinos::{
+    halt, keyboard,
+    memory::{self, BootInfoFrameAllocator},
+    mouse, pci, print, println, serial,
+    vga::{Color, ColorCode, SCREEN},
+};
+use x86_64::VirtAddr;
+
+/// Spins the panic handler waits for each output before giving up
+const PANIC_LOCK_SPINS: usize = 1_000_000;
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!("\nPANIC: {}", info);
-    hlt_loop();
+    use core::fmt::Write;
+
+    // The panicking code may hold either lock, so neither is waited for
+    // forever
+    if let Some(mut port) = serial::SERIAL1.try_lock_for(PANIC_LOCK_SPINS) {
+        writeln!(port, "=== KERNEL PANIC ===\n{}", info).ok();
+    }
+
+    match SCREEN.try_lock_for(PANIC_LOCK_SPINS) {
+        Some(mut screen) => {
+            let red = ColorCode::new(Color::Red, Color::Black);
+            screen
+                .with_color(red, |screen| writeln!(screen, "\n=== KERNEL PANIC ==="))
+                .ok();
+            writeln!(screen, "{}", info).ok();
+            screen.flush();
+        }
+        None => {
+            // Mark the top left corner as a last resort
+            let vga = 0xb8000 as *mut u16;
+            for (i, byte) in b"PANIC".iter().enumerate() {
+                let cell = u16::from(ColorCode::new(Color::White, Color::Red).bits()) << 8
+                    | u16::from(*byte);
+                // SAFETY: 0xb8000 is the identity-mapped VGA buffer and the
+                // first cells are in bounds. Writing past the screen lock can
+                // only garble the output of the lock holder, which is not
+                // going to run again.
+                unsafe { vga.add(i).write_volatile(cell) };
+            }
+        }
+    }
+
+    halt();
 }
 
 bootloader::entry_point!(kernel_main);
 
-pub fn kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
+pub fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
     println!("Kernel starting...");
 
     kleinos::init();
+
+    // SAFETY: The bootloader maps all physical memory at the offset and this
+    // is the only mapper.
+    let mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    if memory::setup_stack_guard(&mapper).is_none() {
+        println!("Kernel stack guard page not found");
+    }
+
+    // SAFETY: The bootloader marks only unused memory as usable and this is
+    // the only frame allocator.
+    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    println!(
+        "{} KiB of usable memory",
+        frame_allocator.usable_frames().count() * 4
+    );
+    if let Err(err) = mouse::init() {
+        println!("PS/2 mouse not available: {:?}", err);
+    }
+    pci::print_devices();
     println!("Kernel init complete");
 
-    hlt_loop();
+    // Echo keyboard and serial input to the screen
+    loop {
+        match serial::pop_byte()
+            .map(char::from)
+            .or_else(keyboard::pop_char)
+        {
+            Some(character) => print!("{}", character),
+            None => x86_64::instructions::hlt(),
+        }
+    }
 }
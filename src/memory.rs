@@ -0,0 +1,214 @@
+//! Memory management.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB, Translate,
+        mapper::{MapToError, UnmapError},
+    },
+};
+
+pub mod heap;
+
+/// Pages searched below the stack pointer for the stack guard page
+const STACK_GUARD_SEARCH_PAGES: u64 = 1024;
+
+/// Virtual address range handed out by `map_mmio`
+const MMIO_START: u64 = 0x4444_0000_0000;
+const MMIO_SIZE: u64 = 1 << 30;
+
+/// Next free address of the MMIO range. Unmapped ranges are not reused.
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Start address of the guard page below the kernel stack, 0 if unknown
+static STACK_GUARD: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a mapper for the active page table.
+///
+/// # Safety
+///
+/// All physical memory must be mapped at `physical_memory_offset`, as done
+/// by the bootloader's `map_physical_memory` feature. Must be called only
+/// once, since the mapper holds a mutable reference to the level 4 table.
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = physical_memory_offset + level_4_frame.start_address().as_u64();
+
+    // SAFETY: CR3 points to the active level 4 table, which the caller
+    // guarantees to be mapped at the offset and not otherwise referenced.
+    unsafe {
+        let level_4_table = &mut *level_4_table.as_mut_ptr::<PageTable>();
+        OffsetPageTable::new(level_4_table, physical_memory_offset)
+    }
+}
+
+/// Maps `page` to `frame` with `flags` and flushes it from the TLB.
+///
+/// Missing page tables are allocated from `frame_allocator`.
+///
+/// # Safety
+///
+/// The caller must ensure that `frame` is not in use elsewhere, otherwise
+/// the mapping creates aliasing memory.
+pub unsafe fn map_page(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    // SAFETY: The caller guarantees that the frame is unused.
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }?.flush();
+    Ok(())
+}
+
+/// Maps the device registers at `phys..phys + size` uncached into the MMIO
+/// range and returns a pointer to `phys`.
+///
+/// Neither `phys` nor `size` needs to be page aligned, all pages touching the
+/// range are mapped. The registers must be accessed with volatile reads and
+/// writes only, otherwise the compiler may merge or drop accesses.
+///
+/// # Safety
+///
+/// The range must belong to a device, or to memory not used elsewhere, as
+/// the mapping aliases it.
+///
+/// # Panics
+///
+/// Panics if the MMIO range has no room left for the mapping.
+pub unsafe fn map_mmio(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys: PhysAddr,
+    size: usize,
+) -> Result<*mut u8, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::containing_address(phys + (size.max(1) - 1) as u64);
+    let frames = PhysFrame::range_inclusive(first, last);
+
+    let start = MMIO_NEXT.fetch_add(frames.len() * 4096, Ordering::Relaxed);
+    assert!(
+        start + frames.len() * 4096 <= MMIO_START + MMIO_SIZE,
+        "MMIO address range exhausted"
+    );
+
+    // Device registers must not be cached, writes must reach the device
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    for (page, frame) in (first_page..).zip(frames) {
+        // SAFETY: The page is in the MMIO range, which is handed out only
+        // once, and the caller guarantees the frame may be aliased.
+        unsafe { map_page(mapper, page, frame, flags, frame_allocator) }?;
+    }
+
+    let offset = phys - first.start_address();
+    Ok((first_page.start_address() + offset).as_mut_ptr())
+}
+
+/// Removes the mapping of `size` bytes at `ptr` created by `map_mmio`.
+///
+/// The virtual addresses are not handed out again.
+///
+/// # Safety
+///
+/// `ptr` and `size` must be the arguments and result of a `map_mmio` call,
+/// and the pointer must not be used afterwards.
+pub unsafe fn unmap_mmio(
+    mapper: &mut OffsetPageTable,
+    ptr: *mut u8,
+    size: usize,
+) -> Result<(), UnmapError> {
+    let addr = VirtAddr::from_ptr(ptr);
+    let first = Page::<Size4KiB>::containing_address(addr);
+    let last = Page::containing_address(addr + (size.max(1) - 1) as u64);
+    for page in Page::range_inclusive(first, last) {
+        mapper.unmap(page)?.1.flush();
+    }
+    Ok(())
+}
+
+/// Returns the physical address `addr` is mapped to, if it is mapped.
+pub fn translate_addr(mapper: &OffsetPageTable, addr: VirtAddr) -> Option<PhysAddr> {
+    mapper.translate_addr(addr)
+}
+
+/// Hands out the usable frames of the bootloader's memory map.
+///
+/// Frames are never returned, there is no deallocation yet.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    // Number of frames handed out so far
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Creates a frame allocator from the bootloader's memory map.
+    ///
+    /// # Safety
+    ///
+    /// All regions marked as usable in `memory_map` must really be unused,
+    /// and only one allocator may be created for the same map.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        Self {
+            memory_map,
+            next: 0,
+        }
+    }
+
+    /// Returns all usable frames of the memory map.
+    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + use<> {
+        self.memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .flat_map(|region| (region.range.start_addr()..region.range.end_addr()).step_by(4096))
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+// SAFETY: The frames come from usable regions of the memory map, which the
+// caller of init guaranteed to be unused, and every frame is returned once.
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next)?;
+        self.next += 1;
+        Some(frame)
+    }
+}
+
+/// Locates and records the guard page below the kernel stack.
+///
+/// The bootloader already leaves the page below the kernel stack unmapped,
+/// so an overflow faults instead of corrupting memory. This finds that page
+/// by walking down from the current stack pointer to the first unmapped
+/// page, so that fault handlers can report an overflow as such via
+/// `is_stack_guard`. Returns `None` if no unmapped page is found.
+///
+/// Must be called on the kernel stack. It does not depend on the GDT or IDT,
+/// but overflows before it has run are reported as plain faults.
+pub fn setup_stack_guard(mapper: &OffsetPageTable) -> Option<Page> {
+    let marker = 0u8;
+    let mut page: Page = Page::containing_address(VirtAddr::from_ptr(&marker));
+
+    for _ in 0..STACK_GUARD_SEARCH_PAGES {
+        page -= 1;
+        if mapper.translate_addr(page.start_address()).is_none() {
+            STACK_GUARD.store(page.start_address().as_u64(), Ordering::Relaxed);
+            return Some(page);
+        }
+    }
+    None
+}
+
+/// Returns whether `addr` lies in the guard page below the kernel stack.
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    let guard = STACK_GUARD.load(Ordering::Relaxed);
+    guard != 0 && addr.align_down(4096u64).as_u64() == guard
+}
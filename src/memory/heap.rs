@@ -0,0 +1,129 @@
+//! The kernel heap backing `alloc`.
+//!
+//! For now the heap lives in a static array in the kernel image, so it is
+//! available without setting up page mappings.
+
+use crate::sync::IrqMutex;
+use core::alloc::{GlobalAlloc, Layout};
+
+pub const HEAP_SIZE: usize = 100 * 1024;
+
+static mut HEAP_MEMORY: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+#[global_allocator]
+static ALLOCATOR: Heap = Heap::new();
+
+/// Hands out the heap memory to `ALLOCATOR`.
+///
+/// Panics if the heap is already initialized.
+pub fn init() {
+    let heap_start = (&raw mut HEAP_MEMORY).cast::<u8>();
+    // SAFETY: HEAP_MEMORY is only accessed through the allocator and the
+    // allocator refuses to be initialized twice, so the region is not handed
+    // out twice.
+    unsafe { ALLOCATOR.0.lock().init(heap_start, HEAP_SIZE) };
+}
+
+/// The global allocator, shared with interrupt handlers through an `IrqMutex`.
+pub struct Heap(IrqMutex<BumpAllocator>);
+
+impl Heap {
+    pub const fn new() -> Self {
+        Self(IrqMutex::new(BumpAllocator::new()))
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// SAFETY: BumpAllocator only returns blocks that fit the layout, lie within
+// the heap region and do not overlap any live allocation.
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        self.0.lock().dealloc();
+    }
+}
+
+/// Hands out memory by moving a pointer forward.
+///
+/// Freed memory is only reused once all allocations are freed. A free list
+/// allocator can replace it behind `Heap` without changes elsewhere.
+#[derive(Debug)]
+pub struct BumpAllocator {
+    heap_start: *mut u8,
+    heap_size: usize,
+    // Offset of the unused part of the heap
+    next: usize,
+    allocations: usize,
+}
+
+// SAFETY: The heap region is owned by the allocator, not by the thread that
+// initialized it, and is only accessed through the allocator.
+unsafe impl Send for BumpAllocator {}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        Self {
+            heap_start: core::ptr::null_mut(),
+            heap_size: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Makes `heap_size` bytes from `heap_start` available for allocation.
+    ///
+    /// Panics if the allocator is already initialized.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid, writable memory that is not used for
+    /// anything else.
+    pub unsafe fn init(&mut self, heap_start: *mut u8, heap_size: usize) {
+        assert!(self.heap_start.is_null(), "heap already initialized");
+
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
+        self.next = 0;
+    }
+
+    /// Returns a block for `layout` or null if the heap is exhausted.
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        // Alignment applies to the address, the block is then derived from
+        // heap_start so it keeps the provenance of the heap region
+        let base = self.heap_start.addr();
+        let Some(start) = (base + self.next).checked_next_multiple_of(layout.align()) else {
+            return core::ptr::null_mut();
+        };
+        let offset = start - base;
+        match offset.checked_add(layout.size()) {
+            Some(end) if end <= self.heap_size => {
+                self.next = end;
+                self.allocations += 1;
+                self.heap_start.wrapping_add(offset)
+            }
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    /// Releases one allocation, reclaiming the whole heap once none is left.
+    pub fn dealloc(&mut self) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = 0;
+        }
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,243 @@
+//! PS/2 mouse input on the auxiliary port of the 8042 controller.
+
+use crate::{
+    collections::RingBuffer,
+    interrupts::{InterruptIndex, register_irq},
+};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub const EVENT_BUFFER_SIZE: usize = 64;
+
+const DATA_PORT: u16 = 0x60;
+/// Status register on read, command register on write
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+// Status register bits
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_ENABLE_AUX: u8 = 0xa8;
+const COMMAND_WRITE_AUX: u8 = 0xd4;
+
+// Controller configuration byte bits
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+// Mouse commands and their acknowledgement
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_ACK: u8 = 0xfa;
+
+/// Status polls before a controller access is considered failed
+const CONTROLLER_TIMEOUT_SPINS: usize = 100_000;
+
+// Bits of the first packet byte
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError {
+    /// The controller did not accept or answer a byte in time
+    Timeout,
+    /// The mouse did not acknowledge a command
+    NoAck,
+}
+
+/// Movement since the previous event and the current button state.
+///
+/// `dy` is positive for upward movement. Movements beyond the `i8` range
+/// are clamped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i8,
+    pub dy: i8,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// The movement packet being received byte by byte
+struct Packet {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+static PACKET: Mutex<Packet> = Mutex::new(Packet {
+    bytes: [0; 3],
+    len: 0,
+});
+
+/// Decoded events waiting for `pop_event`
+static EVENT_BUFFER: RingBuffer<MouseEvent, EVENT_BUFFER_SIZE> = RingBuffer::new();
+
+/// Enables the auxiliary port and data reporting, then starts collecting
+/// events from IRQ 12.
+///
+/// Fails if there is no PS/2 mouse. Runs with interrupts disabled so the
+/// keyboard handler cannot consume the mouse's answers.
+pub fn init() -> Result<(), MouseError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        controller_command(COMMAND_ENABLE_AUX)?;
+
+        controller_command(COMMAND_READ_CONFIG)?;
+        let config = read_data()?;
+        let config = (config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED;
+        controller_command(COMMAND_WRITE_CONFIG)?;
+        write_data(config)?;
+
+        mouse_command(MOUSE_SET_DEFAULTS)?;
+        mouse_command(MOUSE_ENABLE_REPORTING)
+    })?;
+
+    register_irq(InterruptIndex::Mouse.irq(), handle_interrupt);
+    Ok(())
+}
+
+fn status() -> u8 {
+    let mut port = Port::new(STATUS_COMMAND_PORT);
+
+    // SAFETY: 0x64 is the PS/2 controller status port and we are running in
+    // ring 0. Reading the status has no side effects.
+    unsafe { port.read() }
+}
+
+fn wait_for_status(mask: u8, set: bool) -> Result<(), MouseError> {
+    for _ in 0..CONTROLLER_TIMEOUT_SPINS {
+        if (status() & mask != 0) == set {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(MouseError::Timeout)
+}
+
+fn controller_command(command: u8) -> Result<(), MouseError> {
+    wait_for_status(STATUS_INPUT_FULL, false)?;
+    let mut port = Port::new(STATUS_COMMAND_PORT);
+
+    // SAFETY: 0x64 is the PS/2 controller command port, it is ready for a
+    // byte and only the commands defined above are sent.
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+fn write_data(value: u8) -> Result<(), MouseError> {
+    wait_for_status(STATUS_INPUT_FULL, false)?;
+    let mut port = Port::new(DATA_PORT);
+
+    // SAFETY: 0x60 is the PS/2 controller data port and it is ready for a
+    // byte, which goes to the target selected by the preceding command.
+    unsafe { port.write(value) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, MouseError> {
+    wait_for_status(STATUS_OUTPUT_FULL, true)?;
+    let mut port = Port::new(DATA_PORT);
+
+    // SAFETY: 0x60 is the PS/2 controller data port and holds a byte.
+    // Reading only consumes it.
+    Ok(unsafe { port.read() })
+}
+
+/// Sends `command` to the mouse and waits for its acknowledgement.
+fn mouse_command(command: u8) -> Result<(), MouseError> {
+    controller_command(COMMAND_WRITE_AUX)?;
+    write_data(command)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err(MouseError::NoAck),
+    }
+}
+
+/// Adds a 9 bit two's complement sign to a movement byte and clamps the
+/// result to `i8`.
+fn movement(value: u8, negative: bool) -> i8 {
+    let value = if negative {
+        i16::from(value) - 0x100
+    } else {
+        i16::from(value)
+    };
+    value.clamp(i16::from(i8::MIN), i16::from(i8::MAX)) as i8
+}
+
+fn decode_packet([flags, x, y]: [u8; 3]) -> MouseEvent {
+    MouseEvent {
+        dx: movement(x, flags & PACKET_X_SIGN != 0),
+        dy: movement(y, flags & PACKET_Y_SIGN != 0),
+        left: flags & PACKET_LEFT != 0,
+        right: flags & PACKET_RIGHT != 0,
+        middle: flags & PACKET_MIDDLE != 0,
+    }
+}
+
+/// Collects the pending packet byte and queues complete packets.
+///
+/// Called for the mouse IRQ. A first byte without the always-one bit is
+/// dropped to get back in sync with the packet boundaries. Events that do not
+/// fit into the buffer are dropped.
+fn handle_interrupt() {
+    let mut port = Port::new(DATA_PORT);
+
+    // SAFETY: 0x60 is the PS/2 controller data port and we are running in
+    // ring 0. The mouse IRQ signals it holds a byte from the mouse.
+    let byte: u8 = unsafe { port.read() };
+
+    let mut packet = PACKET.lock();
+    if packet.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+        return;
+    }
+
+    let len = packet.len;
+    packet.bytes[len] = byte;
+    packet.len += 1;
+    if packet.len == packet.bytes.len() {
+        packet.len = 0;
+        // SAFETY: The mouse IRQ handler is the only producer and does not
+        // nest, since interrupts are disabled while it runs.
+        let _ = unsafe { EVENT_BUFFER.try_push(decode_packet(packet.bytes)) };
+    }
+}
+
+/// Returns the oldest mouse event, if any.
+///
+/// Intended for the main loop. Must not be called from interrupt handlers,
+/// since the event buffer supports only a single consumer.
+pub fn pop_event() -> Option<MouseEvent> {
+    // SAFETY: Outside of interrupt handlers kleinos runs a single thread, so
+    // there is no concurrent consumer.
+    unsafe { EVENT_BUFFER.try_pop() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_decode_packet() {
+        let event = decode_packet([PACKET_ALWAYS_ONE | PACKET_LEFT | PACKET_Y_SIGN, 5, 0xfe]);
+        assert_eq!(
+            event,
+            MouseEvent {
+                dx: 5,
+                dy: -2,
+                left: true,
+                right: false,
+                middle: false,
+            }
+        );
+
+        // Movements outside of the i8 range are clamped
+        let event = decode_packet([PACKET_ALWAYS_ONE | PACKET_X_SIGN, 0x00, 0xff]);
+        assert_eq!(event.dx, i8::MIN);
+        assert_eq!(event.dy, i8::MAX);
+    }
+}
@@ -0,0 +1,133 @@
+//! PCI configuration space access through the legacy I/O ports 0xCF8 and
+//! 0xCFC (configuration mechanism #1).
+
+use crate::{serial_println, sync::IrqMutex};
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+/// Bit 31 of the address turns the data port access into a config access
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+pub const MAX_SLOTS: u8 = 32;
+pub const MAX_FUNCTIONS: u8 = 8;
+
+/// Vendor ID read for functions that don't exist
+const NO_VENDOR: u16 = 0xffff;
+/// Header type bit set when a device implements functions 1 to 7
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+/// Address and data port, locked together so the two accesses of one
+/// config read are not interleaved with another
+static CONFIG_PORTS: IrqMutex<(Port<u32>, Port<u32>)> =
+    IrqMutex::new((Port::new(CONFIG_ADDRESS_PORT), Port::new(CONFIG_DATA_PORT)));
+
+/// A function found by `scan`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+/// Reads the 32 bit register at `offset` of a function's configuration
+/// space. The offset is rounded down to a multiple of 4.
+pub fn read_config(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    assert!(slot < MAX_SLOTS, "PCI slot {} out of range", slot);
+    assert!(func < MAX_FUNCTIONS, "PCI function {} out of range", func);
+
+    let address = CONFIG_ENABLE
+        | u32::from(bus) << 16
+        | u32::from(slot) << 11
+        | u32::from(func) << 8
+        | u32::from(offset & 0xfc);
+
+    let mut ports = CONFIG_PORTS.lock();
+    let (address_port, data_port) = &mut *ports;
+    // SAFETY: 0xCF8 and 0xCFC are the PCI configuration ports and reading
+    // configuration space has no side effects. We run in ring 0.
+    unsafe {
+        address_port.write(address);
+        data_port.read()
+    }
+}
+
+/// Returns the function at the location, if there is one.
+fn probe(bus: u8, slot: u8, func: u8) -> Option<PciDevice> {
+    let id = read_config(bus, slot, func, 0x00);
+    let vendor = id as u16;
+    if vendor == NO_VENDOR {
+        return None;
+    }
+
+    let class = read_config(bus, slot, func, 0x08);
+    Some(PciDevice {
+        bus,
+        slot,
+        func,
+        vendor,
+        device: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+    })
+}
+
+fn header_type(bus: u8, slot: u8) -> u8 {
+    (read_config(bus, slot, 0, 0x0c) >> 16) as u8
+}
+
+/// Calls `f` for every function present on any bus.
+pub fn scan(mut f: impl FnMut(PciDevice)) {
+    for bus in 0..=u8::MAX {
+        for slot in 0..MAX_SLOTS {
+            let Some(device) = probe(bus, slot, 0) else {
+                continue;
+            };
+            f(device);
+
+            if header_type(bus, slot) & HEADER_MULTIFUNCTION != 0 {
+                (1..MAX_FUNCTIONS)
+                    .filter_map(|func| probe(bus, slot, func))
+                    .for_each(&mut f);
+            }
+        }
+    }
+}
+
+/// Prints all PCI functions over serial, one per line.
+pub fn print_devices() {
+    scan(|device| {
+        serial_println!(
+            "PCI {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}",
+            device.bus,
+            device.slot,
+            device.func,
+            device.vendor,
+            device.device,
+            device.class,
+            device.subclass
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_scan_finds_host_bridge() {
+        // QEMU's machines have a host bridge at 00:00.0
+        let mut host_bridge = None;
+        scan(|device| {
+            if (device.bus, device.slot, device.func) == (0, 0, 0) {
+                host_bridge = Some(device);
+            }
+        });
+        let host_bridge = host_bridge.expect("no PCI host bridge");
+        assert_eq!((host_bridge.class, host_bridge.subclass), (0x06, 0x00));
+    }
+}
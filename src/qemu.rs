@@ -1,22 +1,59 @@
-use crate::hlt_loop;
+//! Exiting QEMU through the isa-debug-exit device.
 
+use crate::halt;
+
+/// I/O port of the isa-debug-exit device, see `test-args` in Cargo.toml
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
     Success = 0x10,
     Failure = 0x11,
 }
 
-pub fn qemu_exit(exit_code: QemuExitCode) -> ! {
+impl QemuExitCode {
+    /// Raw values written to the device. QEMU exits with `(value << 1) | 1`.
+    pub const SUCCESS: u32 = Self::Success as u32;
+    pub const FAILURE: u32 = Self::Failure as u32;
+}
+
+/// Asks QEMU to exit with `exit_code`.
+///
+/// Returns if the isa-debug-exit device is not present, e.g. on real
+/// hardware.
+pub fn try_exit(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
-    let mut port = Port::new(0xf4);
+    let mut port = Port::new(DEBUG_EXIT_PORT);
 
-    // SAFETY: 0xf4 used above is the port configured for QEMU
-    // exit. If it was not configured, it would be ignored and we end
-    // up busy-spinning instead.
+    // SAFETY: 0xf4 is the port configured for QEMU exit. If it was not
+    // configured, the write is ignored.
     unsafe {
         port.write(exit_code as u32);
     };
+}
 
-    hlt_loop();
+/// Returns whether the isa-debug-exit device seems to be present.
+///
+/// This is a heuristic: every write to the device exits QEMU, so it cannot be
+/// probed with a written sentinel. Instead the port is read, which QEMU's
+/// device answers with 0, while a port without any device reads as all ones
+/// on the ISA bus. Other hardware at 0xf4 on real machines can give a false
+/// positive.
+pub fn debug_exit_present() -> bool {
+    use x86_64::instructions::port::Port;
+
+    let mut port: Port<u8> = Port::new(DEBUG_EXIT_PORT);
+
+    // SAFETY: Reading the isa-debug-exit port has no side effects, and an
+    // unused port just reads as 0xff.
+    let value = unsafe { port.read() };
+    value != 0xff
+}
+
+/// Asks QEMU to exit with `exit_code` and halts if that does not happen.
+pub fn qemu_exit(exit_code: QemuExitCode) -> ! {
+    try_exit(exit_code);
+    halt();
 }
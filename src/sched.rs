@@ -0,0 +1,196 @@
+//! A minimal cooperative scheduler.
+//!
+//! Tasks are `fn() -> !` entry points running on stacks from a static pool.
+//! `yield_now` saves the callee-saved registers on the current stack and
+//! switches to the next task in round-robin order. The code that calls
+//! `yield_now` first, usually `kernel_main`, is task 0.
+//!
+//! TODO: preempt tasks from the timer interrupt instead of waiting for them to
+//! yield. That needs the switch to save the full interrupt frame.
+
+use crate::sync::IrqMutex;
+use core::{
+    arch::naked_asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+/// Tasks that can be spawned, not counting task 0
+pub const MAX_TASKS: usize = 4;
+
+/// Size of each task stack
+const TASK_STACK_SIZE: usize = 4096 * 4;
+
+#[allow(dead_code)]
+#[repr(align(16))]
+struct Stack([u8; TASK_STACK_SIZE]);
+
+// Task n runs on TASK_STACKS[n - 1], task 0 keeps the stack it runs on
+static mut TASK_STACKS: [Stack; MAX_TASKS] = [const { Stack([0; TASK_STACK_SIZE]) }; MAX_TASKS];
+
+/// Saved stack pointer of each task that is not running. Written by
+/// `switch_stacks`, outside of the `TASKS` lock.
+static SAVED_RSP: [AtomicU64; MAX_TASKS + 1] = [const { AtomicU64::new(0) }; MAX_TASKS + 1];
+
+static TASKS: IrqMutex<TaskList> = IrqMutex::new(TaskList::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// All task stacks are in use
+    NoFreeStack,
+}
+
+struct TaskList {
+    used: [bool; MAX_TASKS + 1],
+    current: usize,
+}
+
+impl TaskList {
+    const fn new() -> Self {
+        let mut used = [false; MAX_TASKS + 1];
+        used[0] = true;
+        TaskList { used, current: 0 }
+    }
+
+    /// Returns the task after the current one, if there is another task.
+    fn next(&self) -> Option<usize> {
+        (1..=MAX_TASKS)
+            .map(|offset| (self.current + offset) % (MAX_TASKS + 1))
+            .find(|&task| self.used[task])
+    }
+}
+
+/// Starts `entry` as a new task and returns its task number. The task first
+/// runs once another task yields.
+pub fn spawn(entry: fn() -> !) -> Result<usize, SpawnError> {
+    let mut tasks = TASKS.lock();
+    let task = (1..=MAX_TASKS)
+        .find(|&task| !tasks.used[task])
+        .ok_or(SpawnError::NoFreeStack)?;
+
+    // SAFETY: Only the address of the stack is taken, no reference is
+    // created.
+    let stack = unsafe { &raw mut TASK_STACKS[task - 1] };
+    // The first switch pops six registers and returns to task_trampoline
+    // with the stack pointer at the 16 byte aligned end of the stack, as its
+    // call requires. The entry point is passed in rbx.
+    let frame = stack
+        .cast::<u8>()
+        .wrapping_add(TASK_STACK_SIZE - 56)
+        .cast::<u64>();
+    let initial = [
+        0,
+        0,
+        0,
+        0,
+        entry as *const () as u64,
+        0,
+        task_trampoline as *const () as u64,
+    ];
+    // SAFETY: The stack of an unused task is not in use and the frame is
+    // its last 56 bytes, which are 8 byte aligned.
+    unsafe { frame.cast::<[u64; 7]>().write(initial) };
+
+    SAVED_RSP[task].store(frame as u64, Ordering::Relaxed);
+    tasks.used[task] = true;
+    Ok(task)
+}
+
+/// Returns the number of the running task.
+pub fn current_task() -> usize {
+    TASKS.lock().current
+}
+
+/// Switches to the next task. Returns when the other tasks yielded back, or
+/// immediately if there is no other task.
+pub fn yield_now() {
+    // Interrupts stay disabled across the switch. Each task restores its own
+    // interrupt flag when it returns from here, new tasks enable them in
+    // task_start.
+    interrupts::without_interrupts(|| {
+        let (current, next) = {
+            let mut tasks = TASKS.lock();
+            let Some(next) = tasks.next() else {
+                return;
+            };
+            let current = tasks.current;
+            tasks.current = next;
+            (current, next)
+        };
+
+        let new_rsp = SAVED_RSP[next].load(Ordering::Relaxed);
+        // SAFETY: new_rsp was saved by switch_stacks or prepared by spawn,
+        // and the task is not running. Interrupts are disabled, so nothing
+        // else switches tasks before current's stack pointer is saved.
+        unsafe { switch_stacks(SAVED_RSP[current].as_ptr(), new_rsp) };
+    });
+}
+
+/// Saves the callee-saved registers and the stack pointer to `old_rsp`, then
+/// restores them from the stack at `new_rsp` and returns there.
+///
+/// # Safety
+///
+/// `new_rsp` must point to a frame saved by this function or built by
+/// `spawn`, on a stack that nothing else uses.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn switch_stacks(old_rsp: *mut u64, new_rsp: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// First code of a new task, moves the entry point from rbx to the first
+/// argument of `task_start`.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn task_trampoline() -> ! {
+    naked_asm!("mov rdi, rbx", "call {start}", "ud2", start = sym task_start)
+}
+
+// Only called from task_trampoline, which passes the Rust fn pointer as is
+#[allow(improper_ctypes_definitions)]
+extern "sysv64" fn task_start(entry: fn() -> !) -> ! {
+    interrupts::enable();
+    entry()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TASK_RUNS: AtomicU64 = AtomicU64::new(0);
+
+    fn counting_task() -> ! {
+        loop {
+            TASK_RUNS.fetch_add(1, Ordering::Relaxed);
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn test_yield_switches_tasks() {
+        let task = spawn(counting_task).expect("no free task stack");
+        assert_ne!(task, 0);
+        assert_eq!(current_task(), 0);
+
+        yield_now();
+        assert_eq!(TASK_RUNS.load(Ordering::Relaxed), 1);
+        yield_now();
+        assert_eq!(TASK_RUNS.load(Ordering::Relaxed), 2);
+        assert_eq!(current_task(), 0);
+    }
+}
@@ -1,17 +1,491 @@
+//! Driver for the 16550 UART serial ports.
+
+use crate::{
+    collections::RingBuffer,
+    interrupts::{InterruptIndex, register_irq},
+    sync::{Event, IrqMutex},
+};
 use lazy_static::lazy_static;
-use spin::Mutex;
-use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+/// Standard base I/O ports of the PC serial ports
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
+pub const COM3: u16 = 0x3e8;
+pub const COM4: u16 = 0x2e8;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        // SAFETY: 0x3f8 is the I/O port for the first serial port. We
-        // have permissions to access as we are running in ring 0.
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
+        // SAFETY: COM1 is the I/O port for the first serial port and this
+        // is the only driver instance for it. We have permissions to access
+        // as we are running in ring 0.
+        let mut serial_port = unsafe { SerialPort::with_base(COM1) };
+        // Writes to a missing port are dropped by the bus, callers that care
+        // can detect the port by running init again.
+        let _ = serial_port.init();
+        IrqMutex::new(serial_port)
     };
 }
 
+lazy_static! {
+    pub static ref SERIAL2: IrqMutex<SerialPort> = {
+        // SAFETY: COM2 is the I/O port for the second serial port and this
+        // is the only driver instance for it. We have permissions to access
+        // as we are running in ring 0.
+        let mut serial_port = unsafe { SerialPort::with_base(COM2) };
+        // See SERIAL1
+        let _ = serial_port.init();
+        IrqMutex::new(serial_port)
+    };
+}
+
+pub const RECEIVE_BUFFER_SIZE: usize = 256;
+
+/// Bytes received on COM1 by the interrupt handler, waiting for `pop_byte`
+static RECEIVE_BUFFER: RingBuffer<u8, RECEIVE_BUFFER_SIZE> = RingBuffer::new();
+
+/// UART registers as offsets from the base port.
+#[derive(Debug, Clone, Copy)]
+#[repr(u16)]
+enum Register {
+    /// Receive/transmit buffer, divisor latch low byte when DLAB is set
+    Data = 0,
+    /// Interrupt enable, divisor latch high byte when DLAB is set
+    InterruptEnable = 1,
+    /// FIFO control on write, interrupt identification on read
+    FifoControl = 2,
+    LineControl = 3,
+    ModemControl = 4,
+    LineStatus = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// No UART answered the loopback self-test
+    NotPresent,
+    /// The baud rate is not an integer fraction of the 115200 base rate
+    UnsupportedBaudRate,
+}
+
+/// Decoded Line Status Register.
+#[derive(Debug, Clone, Copy)]
+pub struct LineStatus(u8);
+
+impl LineStatus {
+    const DATA_READY: u8 = 1 << 0;
+    const OVERRUN_ERROR: u8 = 1 << 1;
+    const PARITY_ERROR: u8 = 1 << 2;
+    const FRAMING_ERROR: u8 = 1 << 3;
+    const BREAK_INTERRUPT: u8 = 1 << 4;
+    const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+    /// A received byte is waiting in the Data register.
+    pub fn data_ready(self) -> bool {
+        self.0 & Self::DATA_READY != 0
+    }
+
+    /// The transmit holding register can accept another byte.
+    pub fn transmit_empty(self) -> bool {
+        self.0 & Self::TRANSMIT_EMPTY != 0
+    }
+
+    pub fn errors(self) -> LineErrors {
+        LineErrors {
+            overrun: self.0 & Self::OVERRUN_ERROR != 0,
+            parity: self.0 & Self::PARITY_ERROR != 0,
+            framing: self.0 & Self::FRAMING_ERROR != 0,
+            break_interrupt: self.0 & Self::BREAK_INTERRUPT != 0,
+        }
+    }
+}
+
+/// Receive errors reported by the Line Status Register.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineErrors {
+    /// A received byte was lost because the receive buffer was full
+    pub overrun: bool,
+    /// A received byte had the wrong parity
+    pub parity: bool,
+    /// A received byte had no valid stop bit
+    pub framing: bool,
+    /// The line was held low for longer than a full byte
+    pub break_interrupt: bool,
+}
+
+impl LineErrors {
+    pub fn any(self) -> bool {
+        self.overrun || self.parity || self.framing || self.break_interrupt
+    }
+}
+
+const LOOPBACK_TEST_BYTE: u8 = 0xae;
+
+// Interrupt Enable Register bit for "received data available"
+const INTERRUPT_RECEIVED_DATA: u8 = 1 << 0;
+
+// Line Control Register bit holding the transmit line low
+const LINE_CONTROL_BREAK: u8 = 1 << 6;
+
+// Interrupt Identification Register bits set while the FIFOs are enabled
+const FIFO_ENABLED: u8 = 0xc0;
+/// Bytes the transmit FIFO of a 16550A holds
+const TRANSMIT_FIFO_SIZE: usize = 16;
+
+/// Baud rate of the UART clock with a divisor of 1
+const BASE_BAUD_RATE: u32 = 115_200;
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Computes the divisor latch value for `baud`.
+fn baud_divisor(baud: u32) -> Result<u16, SerialError> {
+    if baud == 0 || baud > BASE_BAUD_RATE || !BASE_BAUD_RATE.is_multiple_of(baud) {
+        return Err(SerialError::UnsupportedBaudRate);
+    }
+    // At most 115200, fits easily
+    Ok((BASE_BAUD_RATE / baud) as u16)
+}
+
+#[derive(Debug)]
+pub struct SerialPort {
+    base: u16,
+    // Whether init found working FIFOs
+    fifo: bool,
+    // Whether `write_str` sends newlines as CRLF
+    crlf: bool,
+}
+
+impl SerialPort {
+    /// Creates a driver for the UART at the I/O port `base`, usually one of
+    /// `COM1` to `COM4`.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the base I/O port of a 16550 compatible UART and the
+    /// caller must be allowed to access it. There must only be one
+    /// `SerialPort` per UART.
+    pub const unsafe fn with_base(base: u16) -> Self {
+        Self {
+            base,
+            fifo: false,
+            crlf: true,
+        }
+    }
+
+    fn port(&self, register: Register) -> Port<u8> {
+        Port::new(self.base + register as u16)
+    }
+
+    fn write_register(&mut self, register: Register, value: u8) {
+        // SAFETY: The port is a register of the UART guaranteed by the
+        // contract of `with_base`. Exclusive access is ensured by
+        // `&mut self`.
+        unsafe { self.port(register).write(value) }
+    }
+
+    fn read_register(&self, register: Register) -> u8 {
+        // SAFETY: The port is a register of the UART guaranteed by the
+        // contract of `with_base`. Reading the Line Status and Data registers
+        // only consumes status and received data, other reads have no effect.
+        unsafe { self.port(register).read() }
+    }
+
+    /// Configures the UART for 115200 baud 8N1, see `init_with_baud`.
+    pub fn init(&mut self) -> Result<(), SerialError> {
+        self.init_with_baud(DEFAULT_BAUD_RATE)
+    }
+
+    /// Configures the UART for `baud` 8N1 with FIFOs enabled.
+    ///
+    /// `baud` must divide 115200 evenly. Fails if the UART does not echo a
+    /// test byte in loopback mode, i.e. there is no UART at the base port.
+    pub fn init_with_baud(&mut self, baud: u32) -> Result<(), SerialError> {
+        let [divisor_low, divisor_high] = baud_divisor(baud)?.to_le_bytes();
+
+        // Disable interrupts
+        self.write_register(Register::InterruptEnable, 0x00);
+
+        // Enable DLAB and set the divisor
+        self.write_register(Register::LineControl, 0x80);
+        self.write_register(Register::Data, divisor_low);
+        self.write_register(Register::InterruptEnable, divisor_high);
+
+        // Disable DLAB, 8 data bits, no parity, one stop bit
+        self.write_register(Register::LineControl, 0x03);
+
+        // Enable and clear FIFOs with a 14 byte interrupt threshold. The
+        // original 16450 and buggy 16550 do not report them as enabled.
+        self.write_register(Register::FifoControl, 0xc7);
+        self.fifo = self.read_register(Register::FifoControl) & FIFO_ENABLED == FIFO_ENABLED;
+
+        // Check a byte comes back in loopback mode
+        self.set_loopback(true);
+        self.write_register(Register::Data, LOOPBACK_TEST_BYTE);
+        if self.read_register(Register::Data) != LOOPBACK_TEST_BYTE {
+            return Err(SerialError::NotPresent);
+        }
+
+        self.set_loopback(false);
+        Ok(())
+    }
+
+    /// Switches loopback mode, where sent bytes are received again instead of
+    /// going out on the line.
+    fn set_loopback(&mut self, enable: bool) {
+        if enable {
+            // Loopback with OUT1 and OUT2
+            self.write_register(Register::ModemControl, 0x1e);
+        } else {
+            // Data terminal ready, request to send, OUT1 and OUT2 (IRQ line)
+            self.write_register(Register::ModemControl, 0x0f);
+        }
+    }
+
+    /// Selects whether formatted output sends `\n` as `\r\n`, on by
+    /// default for terminals. Raw writes are never translated.
+    pub fn set_crlf(&mut self, enable: bool) {
+        self.crlf = enable;
+    }
+
+    pub fn line_status(&self) -> LineStatus {
+        LineStatus(self.read_register(Register::LineStatus))
+    }
+
+    /// Returns the receive errors since the Line Status Register was last
+    /// read.
+    ///
+    /// Reading the register clears the error bits, and every other status
+    /// check like `data_ready` or waiting in `write_byte` reads it too, so
+    /// errors are only seen if checked right after receiving.
+    pub fn line_errors(&self) -> LineErrors {
+        self.line_status().errors()
+    }
+
+    /// Starts or stops sending a break by holding the transmit line low.
+    pub fn send_break(&mut self, enable: bool) {
+        let line_control = self.read_register(Register::LineControl);
+        let line_control = if enable {
+            line_control | LINE_CONTROL_BREAK
+        } else {
+            line_control & !LINE_CONTROL_BREAK
+        };
+        self.write_register(Register::LineControl, line_control);
+    }
+
+    fn is_transmit_empty(&self) -> bool {
+        self.line_status().transmit_empty()
+    }
+
+    /// Returns whether a received byte is waiting to be read.
+    pub fn data_ready(&self) -> bool {
+        self.line_status().data_ready()
+    }
+
+    /// Sends `byte` as is, waiting for the transmitter to become ready.
+    pub fn write_byte(&mut self, byte: u8) {
+        while !self.is_transmit_empty() {
+            core::hint::spin_loop();
+        }
+        self.write_register(Register::Data, byte);
+    }
+
+    /// Sends `bytes` as is, filling the transmit FIFO in bursts.
+    ///
+    /// Waits for the transmitter only once per FIFO load instead of once per
+    /// byte. Without a FIFO this is the same as `write_byte` per byte.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        let burst = if self.fifo { TRANSMIT_FIFO_SIZE } else { 1 };
+
+        for chunk in bytes.chunks(burst) {
+            // With FIFOs enabled "transmit empty" means the whole FIFO is
+            // empty
+            while !self.is_transmit_empty() {
+                core::hint::spin_loop();
+            }
+            for &byte in chunk {
+                self.write_register(Register::Data, byte);
+            }
+        }
+    }
+
+    /// Returns whether `init` found working FIFOs.
+    pub fn has_fifo(&self) -> bool {
+        self.fifo
+    }
+
+    /// Waits for and returns the next received byte.
+    pub fn read_byte(&mut self) -> u8 {
+        while !self.data_ready() {
+            core::hint::spin_loop();
+        }
+        self.read_register(Register::Data)
+    }
+
+    /// Raises the UART interrupt whenever a byte is received.
+    ///
+    /// The IRQ of the port must be handled before enabling this.
+    pub fn enable_receive_interrupt(&mut self) {
+        self.write_register(Register::InterruptEnable, INTERRUPT_RECEIVED_DATA);
+    }
+
+    /// Returns the next received byte if one is waiting, without blocking.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        self.data_ready()
+            .then(|| self.read_register(Register::Data))
+    }
+}
+
+/// What `read_line` does with a received byte
+#[derive(Debug, PartialEq, Eq)]
+enum LineEdit {
+    Echo,
+    Erase,
+    Ignore,
+    Done,
+}
+
+/// Applies the received `byte` to the line in `buf[..len]`.
+fn edit_line(buf: &mut [u8], len: &mut usize, byte: u8) -> LineEdit {
+    match byte {
+        b'\r' | b'\n' => LineEdit::Done,
+        // Backspace, or DEL as sent by most terminals
+        0x08 | 0x7f if *len == 0 => LineEdit::Ignore,
+        0x08 | 0x7f => {
+            // Erase a whole UTF-8 character, continuation bytes first
+            *len -= 1;
+            while *len > 0 && buf[*len] & 0xc0 == 0x80 {
+                *len -= 1;
+            }
+            LineEdit::Erase
+        }
+        byte if byte.is_ascii_control() => LineEdit::Ignore,
+        byte => {
+            buf[*len] = byte;
+            *len += 1;
+            LineEdit::Echo
+        }
+    }
+}
+
+/// Returns `bytes` as `&str`, replacing invalid UTF-8 by `?` first.
+fn lossy_str(bytes: &mut [u8]) -> &str {
+    let mut start = 0;
+    while let Err(err) = core::str::from_utf8(&bytes[start..]) {
+        let invalid = start + err.valid_up_to();
+        let invalid_len = err.error_len().unwrap_or(bytes.len() - invalid);
+        bytes[invalid..invalid + invalid_len].fill(b'?');
+        start = invalid + invalid_len;
+    }
+    // The loop above replaced every invalid sequence
+    core::str::from_utf8(bytes).unwrap_or_default()
+}
+
+impl core::fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if !self.crlf {
+            self.write_bytes(s.as_bytes());
+            return Ok(());
+        }
+
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.write_bytes(b"\r\n");
+            }
+            self.write_bytes(line.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// Configures COM1 again and panics if it is missing. Test kernels call this
+/// first, so their results cannot be lost to an absent port.
+pub fn init() {
+    SERIAL1.lock().init().expect("COM1 not present");
+}
+
+/// Starts collecting bytes received on COM1 from its IRQ.
+pub fn init_receive() {
+    register_irq(InterruptIndex::Serial1.irq(), receive_pending);
+    SERIAL1.lock().enable_receive_interrupt();
+}
+
+/// Set by the COM1 IRQ whenever bytes were moved to the receive buffer.
+/// Waiters reset it themselves.
+pub static DATA_RECEIVED: Event = Event::new();
+
+/// Moves all bytes waiting in the COM1 UART into the receive buffer.
+///
+/// Called for the COM1 IRQ. Bytes that do not fit are dropped.
+fn receive_pending() {
+    let mut port = SERIAL1.lock();
+    let mut received = false;
+    while let Some(byte) = port.try_read_byte() {
+        // SAFETY: The COM1 IRQ handler is the only producer and does not
+        // nest, since interrupts are disabled while it runs.
+        let _ = unsafe { RECEIVE_BUFFER.try_push(byte) };
+        received = true;
+    }
+    if received {
+        DATA_RECEIVED.set();
+    }
+}
+
+/// Returns the oldest byte received on COM1, if any.
+///
+/// Intended for the main loop. Must not be called from interrupt handlers,
+/// since the receive buffer supports only a single consumer.
+pub fn pop_byte() -> Option<u8> {
+    // SAFETY: Outside of interrupt handlers kleinos runs a single thread, so
+    // there is no concurrent consumer.
+    unsafe { RECEIVE_BUFFER.try_pop() }
+}
+
+/// Waits for the next byte received on COM1 and returns it.
+///
+/// Halts between checks, so interrupts must be enabled and the receive IRQ
+/// set up by `init_receive`. Same single consumer rule as `pop_byte`.
+pub fn wait_for_byte() -> u8 {
+    loop {
+        if let Some(byte) = pop_byte() {
+            return byte;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Reads a line typed on COM1 into `buf` and returns it without the line
+/// ending.
+///
+/// Typed characters are echoed and backspace erases the last one. The line
+/// ends at `\r` or `\n`, or once `buf` is full. Invalid UTF-8 is replaced by
+/// `?` in `buf`.
+///
+/// Bytes come from the receive buffer like `wait_for_byte`, and COM1 is
+/// locked only to echo, so interrupts keep running while waiting for input.
+pub fn read_line(buf: &mut [u8]) -> &str {
+    read_line_with(buf, wait_for_byte, |bytes| {
+        SERIAL1.lock().write_bytes(bytes)
+    })
+}
+
+/// `read_line` with the input and echo output passed in.
+fn read_line_with(
+    buf: &mut [u8],
+    mut next_byte: impl FnMut() -> u8,
+    mut echo: impl FnMut(&[u8]),
+) -> &str {
+    let mut len = 0;
+    while len < buf.len() {
+        let byte = next_byte();
+        match edit_line(buf, &mut len, byte) {
+            LineEdit::Echo => echo(&[byte]),
+            LineEdit::Erase => echo(b"\x08 \x08"),
+            LineEdit::Ignore => {}
+            LineEdit::Done => break,
+        }
+    }
+    echo(b"\r\n");
+    lossy_str(&mut buf[..len])
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
@@ -23,11 +497,189 @@ macro_rules! serial_println {
     ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints `[file:line] expr = value` over serial and returns the value, like
+/// `std::dbg!`.
+#[macro_export]
+macro_rules! dbg {
+    () => {
+        $crate::serial_println!("[{}:{}]", file!(), line!())
+    };
+    ($val:expr $(,)?) => {
+        // The match keeps temporaries in $val alive, as in std::dbg!
+        match $val {
+            tmp => {
+                $crate::serial_println!(
+                    "[{}:{}] {} = {:?}",
+                    file!(),
+                    line!(),
+                    stringify!($val),
+                    &tmp
+                );
+                tmp
+            }
+        }
+    };
+    ($($val:expr),+ $(,)?) => {
+        ($($crate::dbg!($val)),+,)
+    };
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("serial write failed");
-    });
+    SERIAL1.lock().write_fmt(args).expect("serial write failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collections::ArrayVec;
+
+    #[test_case]
+    fn test_line_status_masks() {
+        let status = LineStatus(LineStatus::DATA_READY);
+        assert!(status.data_ready());
+        assert!(!status.transmit_empty());
+
+        let status = LineStatus(LineStatus::TRANSMIT_EMPTY);
+        assert!(!status.data_ready());
+        assert!(status.transmit_empty());
+    }
+
+    #[test_case]
+    fn test_baud_divisor() {
+        assert_eq!(baud_divisor(115_200), Ok(1));
+        assert_eq!(baud_divisor(38_400), Ok(3));
+        assert_eq!(baud_divisor(9_600), Ok(12));
+        assert_eq!(baud_divisor(50), Ok(2304));
+        assert_eq!(baud_divisor(0), Err(SerialError::UnsupportedBaudRate));
+        assert_eq!(baud_divisor(7_000), Err(SerialError::UnsupportedBaudRate));
+        assert_eq!(baud_divisor(230_400), Err(SerialError::UnsupportedBaudRate));
+    }
+
+    #[test_case]
+    fn test_dbg_returns_value() {
+        crate::dbg!();
+        assert_eq!(crate::dbg!(1 + 2), 3);
+        assert_eq!(crate::dbg!(1, "two",), (1, "two"));
+    }
+
+    #[test_case]
+    fn test_line_errors_decode() {
+        assert!(!LineStatus(LineStatus::DATA_READY).errors().any());
+
+        let errors = LineStatus(LineStatus::PARITY_ERROR | LineStatus::BREAK_INTERRUPT).errors();
+        assert_eq!(
+            errors,
+            LineErrors {
+                overrun: false,
+                parity: true,
+                framing: false,
+                break_interrupt: true,
+            }
+        );
+    }
+
+    #[test_case]
+    fn test_fifo_detected() {
+        assert!(SERIAL1.lock().has_fifo());
+    }
+
+    #[test_case]
+    fn test_crlf_translation() {
+        use core::fmt::Write;
+
+        let mut port = SERIAL1.lock();
+        port.set_loopback(true);
+
+        writeln!(port, "a").expect("serial write failed");
+        let crlf = [port.read_byte(), port.read_byte(), port.read_byte()];
+
+        port.set_crlf(false);
+        writeln!(port, "a").expect("serial write failed");
+        let lf = [port.read_byte(), port.read_byte()];
+        port.set_crlf(true);
+
+        port.set_loopback(false);
+        assert_eq!(&crlf, b"a\r\n");
+        assert_eq!(&lf, b"a\n");
+    }
+
+    #[test_case]
+    fn test_line_editing() {
+        let mut buf = [0; 8];
+        let mut len = 0;
+        let mut edits = [LineEdit::Ignore, LineEdit::Ignore, LineEdit::Ignore];
+        for (edit, &byte) in edits.iter_mut().zip(b"ab\x7f") {
+            *edit = edit_line(&mut buf, &mut len, byte);
+        }
+        assert_eq!(edits, [LineEdit::Echo, LineEdit::Echo, LineEdit::Erase]);
+        assert_eq!(&buf[..len], b"a");
+
+        // Backspace removes a multi-byte character at once
+        for &byte in "\u{e9}".as_bytes() {
+            edit_line(&mut buf, &mut len, byte);
+        }
+        assert_eq!(len, 3);
+        assert_eq!(edit_line(&mut buf, &mut len, 0x08), LineEdit::Erase);
+        assert_eq!(len, 1);
+
+        assert_eq!(edit_line(&mut buf, &mut len, 0x1b), LineEdit::Ignore);
+        assert_eq!(edit_line(&mut buf, &mut len, b'\r'), LineEdit::Done);
+        assert_eq!(len, 1);
+    }
+
+    #[test_case]
+    fn test_lossy_str() {
+        let mut bytes = *b"a\xffb\xc3";
+        assert_eq!(lossy_str(&mut bytes), "a?b?");
+        let mut bytes = *b"ok \xc3\xa9";
+        assert_eq!(lossy_str(&mut bytes), "ok \u{e9}");
+    }
+
+    /// Sends `bytes` to COM1 in loopback mode, so the receive IRQ moves them
+    /// to the receive buffer.
+    fn feed_loopback(bytes: &[u8]) {
+        let mut port = SERIAL1.lock();
+        port.set_loopback(true);
+        port.write_bytes(bytes);
+        while !port.line_status().transmit_empty() {
+            core::hint::spin_loop();
+        }
+        port.set_loopback(false);
+    }
+
+    /// Runs `read_line_with` on the receive buffer, collecting the echo.
+    fn read_fed_line<'a>(buf: &'a mut [u8], echo: &mut ArrayVec<u8, 32>) -> &'a str {
+        read_line_with(buf, wait_for_byte, |bytes| {
+            for &byte in bytes {
+                echo.push(byte).expect("echo too long");
+            }
+        })
+    }
+
+    #[test_case]
+    fn test_read_line_loopback() {
+        let mut buf = [0; 8];
+        let mut echo = ArrayVec::new();
+        feed_loopback(b"ab\x7fc\r");
+        assert_eq!(read_fed_line(&mut buf, &mut echo), "ac");
+        assert_eq!(echo.as_slice(), b"ab\x08 \x08c\r\n");
+
+        // A full buffer ends the line without a line ending
+        let mut buf = [0; 3];
+        let mut echo = ArrayVec::new();
+        feed_loopback(b"xyz");
+        assert_eq!(read_fed_line(&mut buf, &mut echo), "xyz");
+        assert_eq!(echo.as_slice(), b"xyz\r\n");
+        assert_eq!(pop_byte(), None);
+    }
+
+    #[test_case]
+    fn test_receive_irq_sets_event() {
+        DATA_RECEIVED.reset();
+        feed_loopback(b"e");
+        DATA_RECEIVED.wait();
+        assert_eq!(pop_byte(), Some(b'e'));
+    }
 }
@@ -0,0 +1,639 @@
+//! Synchronization primitives for data shared with interrupt handlers.
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicPtr;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering, fence},
+};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// A spinlock that keeps interrupts disabled while it is held.
+///
+/// Data also used by interrupt handlers must be protected by an `IrqMutex`:
+/// with a plain `Mutex` a handler interrupting the holder spins forever on
+/// the lock.
+///
+/// Debug builds remember where the lock was taken and report the holder
+/// over serial when `lock` spins for a long time.
+#[derive(Debug, Default)]
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+    // Caller that took the lock, null while unlocked
+    #[cfg(debug_assertions)]
+    holder: AtomicPtr<Location<'static>>,
+}
+
+/// Releases the lock and then restores the previous interrupt state on drop.
+#[derive(Debug)]
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+    #[cfg(debug_assertions)]
+    holder: &'a AtomicPtr<Location<'static>>,
+}
+
+/// Failed attempts in `lock` after which a debug build reports the holder
+#[cfg(debug_assertions)]
+const CONTENTION_REPORT_RETRIES: usize = 1_000_000;
+
+/// `Backoff` doubles its spins up to 2^BACKOFF_MAX_STEP per snooze
+const BACKOFF_MAX_STEP: u32 = 6;
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            #[cfg(debug_assertions)]
+            holder: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Returns whether the lock is currently held.
+    ///
+    /// Only a snapshot, the lock may be taken or released right after.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Returns where the current holder took the lock, if it is held.
+    ///
+    /// Always `None` in release builds.
+    pub fn holder(&self) -> Option<&'static Location<'static>> {
+        #[cfg(debug_assertions)]
+        {
+            let holder = self.holder.load(Ordering::Relaxed);
+            // SAFETY: holder is either null or was stored from a
+            // &'static Location in `guard`.
+            unsafe { holder.as_ref() }
+        }
+        #[cfg(not(debug_assertions))]
+        None
+    }
+
+    /// Disables interrupts and spins until the lock is acquired.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let mut backoff = Backoff::new();
+        #[cfg(debug_assertions)]
+        let mut retries = 0;
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            #[cfg(debug_assertions)]
+            {
+                retries += 1;
+                if retries == CONTENTION_REPORT_RETRIES {
+                    report_contention(self.holder());
+                }
+            }
+            backoff.snooze();
+        };
+
+        self.guard(guard, interrupts_were_enabled)
+    }
+
+    /// Wraps a guard of the inner lock, remembering the caller as holder.
+    #[track_caller]
+    fn guard<'a>(
+        &'a self,
+        guard: MutexGuard<'a, T>,
+        interrupts_were_enabled: bool,
+    ) -> IrqMutexGuard<'a, T> {
+        #[cfg(debug_assertions)]
+        self.holder.store(
+            (Location::caller() as *const Location<'static>).cast_mut(),
+            Ordering::Relaxed,
+        );
+
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            interrupts_were_enabled,
+            #[cfg(debug_assertions)]
+            holder: &self.holder,
+        }
+    }
+
+    /// Acquires the lock if it is free, leaving interrupts untouched if not.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.guard(guard, interrupts_were_enabled)),
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Acquires the lock or panics, naming the holder in debug builds.
+    ///
+    /// Useful to turn a suspected deadlock into a diagnosable panic.
+    #[track_caller]
+    pub fn try_lock_or_panic(&self) -> IrqMutexGuard<'_, T> {
+        match self.try_lock() {
+            Some(guard) => guard,
+            None => match self.holder() {
+                Some(holder) => panic!("IrqMutex already locked at {}", holder),
+                None => panic!("IrqMutex already locked"),
+            },
+        }
+    }
+
+    /// Runs `f` on the protected data with the lock held.
+    #[track_caller]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// Returns the protected data without locking.
+    ///
+    /// `&mut self` proves there is no other reference to the mutex, so no
+    /// lock or interrupt masking is needed, e.g. before a static is shared.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Tries to acquire the lock up to `max_spins` times before giving up.
+    ///
+    /// Useful where waiting forever is not an option, e.g. in the panic
+    /// handler when the panicking code may hold the lock.
+    #[track_caller]
+    pub fn try_lock_for(&self, max_spins: usize) -> Option<IrqMutexGuard<'_, T>> {
+        for _ in 0..max_spins {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.holder.store(core::ptr::null_mut(), Ordering::Relaxed);
+
+        // SAFETY: The guard is dropped exactly once, here, and not used
+        // afterwards. It must be released before interrupts are enabled again
+        // so a handler cannot find the lock taken.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Reports a lock that could not be taken for a long time over serial.
+///
+/// Skipped if COM1 itself is locked, which may well be the contended lock.
+#[cfg(debug_assertions)]
+fn report_contention(holder: Option<&Location>) {
+    use core::fmt::Write;
+
+    if let Some(mut port) = crate::serial::SERIAL1.try_lock() {
+        match holder {
+            Some(holder) => writeln!(port, "IrqMutex contended, held since {}", holder).ok(),
+            None => writeln!(port, "IrqMutex contended").ok(),
+        };
+    }
+}
+
+/// Exponential backoff for spin loops.
+///
+/// Each `snooze` spins twice as long as the previous one, up to a cap, so
+/// contended waiters poll the shared cache line less and less often.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    /// Spins for the current delay and doubles it for the next call.
+    pub fn snooze(&mut self) {
+        for _ in 0..1u32 << self.step {
+            core::hint::spin_loop();
+        }
+        if self.step < BACKOFF_MAX_STEP {
+            self.step += 1;
+        }
+    }
+
+    /// Starts over with the shortest delay, e.g. after making progress.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+}
+
+/// A counting semaphore handing out up to `count` permits.
+///
+/// Waiters spin and there is no fairness guarantee: a released permit goes
+/// to whichever waiter happens to see it first.
+#[derive(Debug)]
+pub struct Semaphore {
+    permits: AtomicUsize,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(count),
+        }
+    }
+
+    /// Spins until a permit is available and takes it.
+    pub fn acquire(&self) {
+        let mut backoff = Backoff::new();
+        while !self.try_acquire() {
+            backoff.snooze();
+        }
+    }
+
+    /// Takes a permit if one is available.
+    pub fn try_acquire(&self) -> bool {
+        // Acquire pairs with the Release in `release`, like locking a mutex
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Returns a permit.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+    }
+
+    /// Currently available permits.
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+/// A resettable completion flag, set by an interrupt handler and waited for
+/// by normal code.
+#[derive(Debug, Default)]
+pub struct Event {
+    set: AtomicBool,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+        }
+    }
+
+    /// Spins until the event is set.
+    ///
+    /// Must be called with interrupts enabled when a handler sets the event,
+    /// otherwise the handler never runs and this spins forever.
+    pub fn wait(&self) {
+        let mut backoff = Backoff::new();
+        while !self.is_set() {
+            backoff.snooze();
+        }
+    }
+
+    /// Sets the event, releasing all waiters until it is reset.
+    pub fn set(&self) {
+        // Release pairs with the Acquire in `is_set`, so waiters see what
+        // was done before setting
+        self.set.store(true, Ordering::Release);
+    }
+
+    pub fn reset(&self) {
+        self.set.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+}
+
+/// A reusable rendezvous point for a fixed number of CPUs or tasks.
+///
+/// `wait` spins until `n` participants arrived, then releases all of them
+/// and the barrier starts over for the next round.
+#[derive(Debug)]
+pub struct Barrier {
+    participants: usize,
+    arrived: AtomicUsize,
+    // Completed rounds, waiters spin until it changes
+    generation: AtomicUsize,
+}
+
+impl Barrier {
+    pub const fn new(n: usize) -> Self {
+        assert!(n > 0, "a barrier needs at least one participant");
+        Self {
+            participants: n,
+            arrived: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Spins until all participants of this round called `wait`.
+    pub fn wait(&self) {
+        let mut backoff = Backoff::new();
+        self.wait_with(|| backoff.snooze());
+    }
+
+    /// Like `wait`, but calls `relax` while waiting for the others, e.g.
+    /// `sched::yield_now` so that cooperative tasks can arrive.
+    pub fn wait_with(&self, mut relax: impl FnMut()) {
+        // Read before arriving, so a round completing right after still
+        // counts as a change
+        let generation = self.generation.load(Ordering::Acquire);
+
+        // AcqRel makes everything the participants did before arriving
+        // visible to the last one, which publishes it with the Release below
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 == self.participants {
+            // Reset before the release, so participants that go on to the next
+            // round count from 0
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            return;
+        }
+
+        while self.generation.load(Ordering::Acquire) == generation {
+            relax();
+        }
+    }
+}
+
+/// A sequence lock for small `Copy` data that is read often and written
+/// rarely, e.g. by an interrupt handler.
+///
+/// Readers never block a writer: they copy the data and retry if a write
+/// happened meanwhile, detected by the sequence number that is odd during
+/// writes. Writers exclude each other and write with interrupts disabled, so
+/// a handler on the same CPU never reads a half written value.
+pub struct SeqLock<T> {
+    seq: AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: Writes are serialized by the sequence number and readers discard
+// any copy that overlapped a write, so only complete values are handed out.
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns a copy of the data, retrying while a write is in progress.
+    pub fn read(&self) -> T {
+        let mut backoff = Backoff::new();
+        loop {
+            // Acquire pairs with the Release store ending a write, so the
+            // data of that write is visible
+            let start = self.seq.load(Ordering::Acquire);
+            if start % 2 == 1 {
+                backoff.snooze();
+                continue;
+            }
+            // SAFETY: The pointer is valid and T is Copy. The copy may race
+            // with a writer, in which case the sequence check below fails
+            // and it is discarded without being used.
+            let value = unsafe { self.data.get().read_volatile() };
+            // Keeps the data read above before the second sequence read
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == start {
+                return value;
+            }
+        }
+    }
+
+    /// Replaces the data, waiting for other writers to finish first.
+    pub fn write(&self, value: T) {
+        interrupts::without_interrupts(|| {
+            let mut backoff = Backoff::new();
+            let mut seq = self.seq.load(Ordering::Relaxed);
+            loop {
+                if seq % 2 == 1 {
+                    backoff.snooze();
+                    seq = self.seq.load(Ordering::Relaxed);
+                    continue;
+                }
+                match self.seq.compare_exchange_weak(
+                    seq,
+                    seq + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => seq = current,
+                }
+            }
+            // Keeps the odd sequence number before the data writes, so
+            // readers see that a write started
+            fence(Ordering::Release);
+            // SAFETY: The odd sequence number excludes other writers and
+            // readers discard what they copied meanwhile.
+            unsafe { self.data.get().write_volatile(value) };
+            self.seq.store(seq + 2, Ordering::Release);
+        });
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeqLock")
+            .field("data", &self.read())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_irq_mutex_restores_interrupts() {
+        let mutex = IrqMutex::new(0);
+        assert!(interrupts::are_enabled());
+
+        {
+            let mut guard = mutex.lock();
+            assert!(!interrupts::are_enabled());
+            *guard += 1;
+        }
+        assert!(interrupts::are_enabled());
+
+        interrupts::without_interrupts(|| {
+            drop(mutex.lock());
+            assert!(!interrupts::are_enabled());
+        });
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test_case]
+    fn test_irq_mutex_try_lock() {
+        let mutex = IrqMutex::new(());
+        let guard = mutex.try_lock();
+        assert!(guard.is_some());
+        assert!(mutex.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+        drop(guard);
+        assert!(interrupts::are_enabled());
+    }
+
+    #[test_case]
+    fn test_irq_mutex_with_and_get_mut() {
+        let mut mutex = IrqMutex::new(1);
+        let doubled = mutex.with(|value| {
+            *value *= 2;
+            *value
+        });
+        assert_eq!(doubled, 2);
+        assert!(interrupts::are_enabled());
+
+        *mutex.get_mut() += 1;
+        assert_eq!(*mutex.lock(), 3);
+    }
+
+    #[test_case]
+    fn test_irq_mutex_try_lock_for() {
+        let mutex = IrqMutex::new(());
+        let guard = mutex.lock();
+        assert!(mutex.try_lock_for(100).is_none());
+        drop(guard);
+        assert!(mutex.try_lock_for(1).is_some());
+        assert!(mutex.try_lock_for(0).is_none());
+    }
+
+    #[test_case]
+    fn test_semaphore_counts_permits() {
+        let semaphore = Semaphore::new(2);
+        assert!(semaphore.try_acquire());
+        semaphore.acquire();
+        assert!(!semaphore.try_acquire());
+        assert_eq!(semaphore.available(), 0);
+
+        semaphore.release();
+        assert!(semaphore.try_acquire());
+        semaphore.release();
+        semaphore.release();
+        assert_eq!(semaphore.available(), 2);
+    }
+
+    #[test_case]
+    fn test_irq_mutex_holder() {
+        let mutex = IrqMutex::new(());
+        assert!(!mutex.is_locked());
+        assert!(mutex.holder().is_none());
+
+        let guard = mutex.lock();
+        assert!(mutex.is_locked());
+        if cfg!(debug_assertions) {
+            let holder = mutex.holder().expect("holder not recorded");
+            assert_eq!(holder.file(), file!());
+        }
+        drop(guard);
+        assert!(mutex.holder().is_none());
+    }
+
+    #[test_case]
+    fn test_seq_lock_read_write() {
+        let lock = SeqLock::new((1u64, 2u64));
+        assert_eq!(lock.read(), (1, 2));
+        lock.write((3, 4));
+        assert_eq!(lock.read(), (3, 4));
+        // Each write advances the sequence by two, leaving it even
+        assert_eq!(lock.seq.load(Ordering::Relaxed), 2);
+    }
+
+    #[test_case]
+    fn test_barrier_reuse() {
+        use crate::sched;
+
+        const ROUNDS: usize = 5;
+        static BARRIER: Barrier = Barrier::new(2);
+        static TASK_ROUNDS: AtomicUsize = AtomicUsize::new(0);
+
+        fn barrier_task() -> ! {
+            loop {
+                BARRIER.wait_with(sched::yield_now);
+                TASK_ROUNDS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        sched::spawn(barrier_task).expect("no free task stack");
+        // Either side arrives last in some rounds. A round can only complete
+        // once this task arrived again, even if the other task already went
+        // on to wait for the next one.
+        for round in 1..=ROUNDS {
+            BARRIER.wait_with(sched::yield_now);
+            assert_eq!(BARRIER.generation.load(Ordering::Relaxed), round);
+            assert!(BARRIER.arrived.load(Ordering::Relaxed) <= 1);
+        }
+
+        // Let the task finish its last round
+        sched::yield_now();
+        assert_eq!(TASK_ROUNDS.load(Ordering::Relaxed), ROUNDS);
+    }
+
+    #[test_case]
+    fn test_event_set_and_reset() {
+        let event = Event::new();
+        assert!(!event.is_set());
+        event.set();
+        // Returns right away once set
+        event.wait();
+        assert!(event.is_set());
+        event.reset();
+        assert!(!event.is_set());
+    }
+
+    #[test_case]
+    fn test_backoff_doubles_up_to_cap() {
+        let mut backoff = Backoff::new();
+        backoff.snooze();
+        assert_eq!(backoff.step, 1);
+        for _ in 0..2 * BACKOFF_MAX_STEP {
+            backoff.snooze();
+        }
+        assert_eq!(backoff.step, BACKOFF_MAX_STEP);
+        backoff.reset();
+        assert_eq!(backoff.step, 0);
+    }
+}
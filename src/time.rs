@@ -0,0 +1,303 @@
+//! Delays based on the PIT timer ticks and the timestamp counter, and the
+//! wall-clock time of the CMOS real-time clock.
+
+use crate::{cpu, interrupts};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Rough `busy_spin` iterations per millisecond, used while the timer is not
+/// running. Not calibrated, expect the delay to be off by a large factor.
+const FALLBACK_SPINS_PER_MS: usize = 100_000;
+
+/// Timer ticks to measure the timestamp counter against in `calibrate_tsc`
+const CALIBRATION_TICKS: u64 = 5;
+
+/// Timestamp counter cycles per millisecond, 0 until calibrated
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Measures the timestamp counter rate against the timer for `delay_us`.
+///
+/// Blocks for a few timer ticks. Does nothing if the timer is not running.
+pub fn calibrate_tsc() {
+    if !interrupts::timer_running() {
+        return;
+    }
+
+    // Start right after a tick so whole ticks are measured
+    let start_tick = wait_for_next_tick(interrupts::ticks());
+    let start_tsc = cpu::rdtsc();
+    let mut tick = start_tick;
+    while tick - start_tick < CALIBRATION_TICKS {
+        tick = wait_for_next_tick(tick);
+    }
+    let cycles = cpu::rdtsc() - start_tsc;
+
+    // Microseconds from the exact PIT divisor, whole milliseconds would be 0
+    // for fast timers and skew the result for most others
+    let elapsed_us = interrupts::ticks_to_us(CALIBRATION_TICKS);
+    if elapsed_us == 0 {
+        return;
+    }
+    let cycles_per_ms = u128::from(cycles) * 1000 / u128::from(elapsed_us);
+    if let Ok(cycles_per_ms @ 1..) = u64::try_from(cycles_per_ms) {
+        TSC_PER_MS.store(cycles_per_ms, Ordering::Relaxed);
+    }
+}
+
+fn wait_for_next_tick(tick: u64) -> u64 {
+    loop {
+        let now = interrupts::ticks();
+        if now != tick {
+            return now;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns the calibrated timestamp counter cycles per millisecond.
+pub fn tsc_per_ms() -> Option<u64> {
+    match TSC_PER_MS.load(Ordering::Relaxed) {
+        0 => None,
+        cycles => Some(cycles),
+    }
+}
+
+/// Spins until the timestamp counter advanced by `cycles`.
+pub fn busy_wait_cycles(cycles: u64) {
+    let start = cpu::rdtsc();
+    while cpu::rdtsc().wrapping_sub(start) < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits for at least `us` microseconds, for delays shorter than a tick.
+///
+/// Requires `calibrate_tsc`, falls back to `sleep_ms` otherwise.
+pub fn delay_us(us: u64) {
+    match tsc_per_ms() {
+        Some(cycles_per_ms) => busy_wait_cycles(us.saturating_mul(cycles_per_ms) / 1000),
+        None => sleep_ms(us.div_ceil(1000)),
+    }
+}
+
+/// Waits for at least `ms` milliseconds.
+///
+/// Interrupts must be enabled and the timer initialized for the delay to be
+/// measured, otherwise this falls back to an uncalibrated `busy_spin`.
+pub fn sleep_ms(ms: u64) {
+    if !interrupts::timer_running() {
+        crate::busy_spin(FALLBACK_SPINS_PER_MS.saturating_mul(ms as usize));
+        return;
+    }
+
+    // Wait one tick longer as the current tick may be almost over
+    let start = interrupts::uptime_ms();
+    let tick_ms = u64::from(1000 / interrupts::timer_frequency()).max(1);
+    while interrupts::uptime_ms() - start < ms + tick_ms {
+        core::hint::spin_loop();
+    }
+}
+
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+// Not standardized, ACPI reports it in the FADT but it is almost always here
+const RTC_CENTURY: u8 = 0x32;
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+// Set in the hours register for PM in 12 hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Raw register values as stored by the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RtcRegisters {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_cmos(register: u8) -> u8 {
+    let mut index = Port::new(CMOS_INDEX_PORT);
+    let mut data = Port::new(CMOS_DATA_PORT);
+
+    // SAFETY: 0x70/0x71 are the CMOS index and data ports and we are running
+    // in ring 0. Bit 7 of the index stays clear so NMIs remain enabled.
+    // Callers disable interrupts so the index/data pair is not interleaved.
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+fn read_rtc_registers() -> RtcRegisters {
+    while read_cmos(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    RtcRegisters {
+        second: read_cmos(RTC_SECONDS),
+        minute: read_cmos(RTC_MINUTES),
+        hour: read_cmos(RTC_HOURS),
+        day: read_cmos(RTC_DAY),
+        month: read_cmos(RTC_MONTH),
+        year: read_cmos(RTC_YEAR),
+        century: read_cmos(RTC_CENTURY),
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the current date and time from the CMOS real-time clock.
+///
+/// The RTC usually runs in local time, not UTC.
+pub fn read_rtc() -> DateTime {
+    let (mut registers, status_b) = x86_64::instructions::interrupts::without_interrupts(|| {
+        // An update may still start between the check and the reads, read
+        // until two consecutive reads agree.
+        let mut registers = read_rtc_registers();
+        loop {
+            let again = read_rtc_registers();
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        (registers, read_cmos(RTC_STATUS_B))
+    });
+
+    let pm = registers.hour & HOUR_PM != 0;
+    registers.hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        for value in [
+            &mut registers.second,
+            &mut registers.minute,
+            &mut registers.hour,
+            &mut registers.day,
+            &mut registers.month,
+            &mut registers.year,
+            &mut registers.century,
+        ] {
+            *value = bcd_to_binary(*value);
+        }
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        registers.hour = registers.hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    // Without a plausible century register assume the 21st century
+    let century = match registers.century {
+        19..=99 => u16::from(registers.century),
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(registers.year),
+        month: registers.month,
+        day: registers.day,
+        hour: registers.hour,
+        minute: registers.minute,
+        second: registers.second,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_sleep_ms() {
+        let start = interrupts::uptime_ms();
+        sleep_ms(20);
+        assert!(interrupts::uptime_ms() - start >= 20);
+    }
+
+    #[test_case]
+    fn test_delay_us() {
+        assert!(tsc_per_ms().is_some());
+
+        let start = interrupts::uptime_ms();
+        delay_us(30_000);
+        let elapsed = interrupts::uptime_ms() - start;
+        // uptime has tick granularity, a badly calibrated rate is off by
+        // much more than that
+        let tick_ms = interrupts::ticks_to_us(1).div_ceil(1000);
+        assert!(elapsed + tick_ms >= 30, "delay too short: {} ms", elapsed);
+        assert!(
+            elapsed <= 30 + 2 * tick_ms,
+            "delay too long: {} ms",
+            elapsed
+        );
+    }
+
+    #[test_case]
+    fn test_calibrate_tsc_fast_timer() {
+        let calibrated = tsc_per_ms().expect("TSC not calibrated");
+
+        // 5 ticks at 8 kHz last less than a millisecond
+        interrupts::init_timer(8000).expect("8 kHz timer rejected");
+        calibrate_tsc();
+        let fast = tsc_per_ms();
+        interrupts::init_timer(interrupts::DEFAULT_TIMER_FREQUENCY)
+            .expect("default timer frequency is supported");
+        calibrate_tsc();
+
+        let fast = fast.expect("TSC not calibrated at 8 kHz");
+        // Tick latency matters more for such a short measurement
+        assert!(fast.abs_diff(calibrated) < calibrated / 4);
+    }
+
+    #[test_case]
+    fn test_bcd_to_binary() {
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(bcd_to_binary(0x09), 9);
+        assert_eq!(bcd_to_binary(0x59), 59);
+    }
+
+    #[test_case]
+    fn test_read_rtc_is_plausible() {
+        let now = read_rtc();
+        assert!(now.year >= 2000);
+        assert!((1..=12).contains(&now.month));
+        assert!((1..=31).contains(&now.day));
+        assert!(now.hour < 24);
+        assert!(now.minute < 60);
+        assert!(now.second < 60);
+    }
+}
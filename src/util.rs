@@ -0,0 +1,170 @@
+//! Small debugging and formatting helpers.
+
+use core::{fmt, str};
+
+use crate::{print, println};
+
+const HEXDUMP_BYTES_PER_LINE: usize = 16;
+
+/// Prints `len` bytes starting at `addr` to the screen, 16 bytes per line as
+/// `offset: hex bytes | ascii`. Non-printable bytes are shown as `.`.
+///
+/// # Safety
+///
+/// The caller must ensure that `addr..addr + len` is mapped and readable.
+/// Bytes are read with volatile reads, so device memory can be dumped too.
+pub unsafe fn hexdump(addr: *const u8, len: usize) {
+    let mut line = [0u8; HEXDUMP_BYTES_PER_LINE];
+
+    for offset in (0..len).step_by(HEXDUMP_BYTES_PER_LINE) {
+        let count = HEXDUMP_BYTES_PER_LINE.min(len - offset);
+        for (i, byte) in line[..count].iter_mut().enumerate() {
+            // SAFETY: offset + i < len and the caller guarantees that the
+            // whole range is readable.
+            *byte = unsafe { addr.add(offset + i).read_volatile() };
+        }
+        print_hexdump_line(offset, &line[..count]);
+    }
+}
+
+fn print_hexdump_line(offset: usize, bytes: &[u8]) {
+    print!("{:08x}:", offset);
+    for i in 0..HEXDUMP_BYTES_PER_LINE {
+        match bytes.get(i) {
+            Some(byte) => print!(" {:02x}", byte),
+            None => print!("   "),
+        }
+    }
+    print!(" | ");
+    for &byte in bytes {
+        let c = if byte.is_ascii_graphic() || byte == b' ' {
+            char::from(byte)
+        } else {
+            '.'
+        };
+        print!("{}", c);
+    }
+    println!();
+}
+
+/// A string formatted into a fixed stack buffer of `N` bytes, for building
+/// text without the heap.
+///
+/// Writes that don't fit are cut at the last whole character and fail with
+/// `fmt::Error`, so the contents are always valid UTF-8.
+#[derive(Clone, Copy)]
+pub struct FixedString<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedString<N> {
+    pub const fn new() -> Self {
+        FixedString {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.buf[..self.len];
+        match str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(err) => str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for FixedString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for FixedString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = N - self.len;
+        let mut count = s.len().min(free);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        if count < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<const N: usize> fmt::Debug for FixedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> fmt::Display for FixedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixedString, hexdump};
+    use crate::vga::SCREEN;
+
+    #[test_case]
+    fn test_hexdump_line() {
+        let data = *b"AB\x00\x7fcd";
+        // SAFETY: data is a local array of data.len() bytes.
+        unsafe { hexdump(data.as_ptr(), data.len()) };
+
+        let row = SCREEN.lock().row_text(1);
+        let expected = b"00000000: 41 42 00 7f 63 64";
+        assert_eq!(&row[..expected.len()], expected);
+        let ascii = b"| AB..cd";
+        let start = 9 + 3 * 16 + 1;
+        assert_eq!(&row[start..start + ascii.len()], ascii);
+    }
+
+    #[test_case]
+    fn test_fixed_string_write() {
+        use core::fmt::Write;
+
+        let mut s = FixedString::<16>::new();
+        write!(s, "[{:>5}] ok", 42).expect("FixedString write failed");
+        assert_eq!(s.as_str(), "[   42] ok");
+        s.clear();
+        assert!(s.is_empty());
+    }
+
+    #[test_case]
+    fn test_fixed_string_overflow() {
+        use core::fmt::Write;
+
+        let mut s = FixedString::<4>::new();
+        assert!(write!(s, "abcdef").is_err());
+        assert_eq!(s.as_str(), "abcd");
+        assert!(s.write_str("x").is_err());
+        assert_eq!(s.len(), 4);
+
+        // A character that doesn't fit completely is dropped
+        let mut s = FixedString::<4>::new();
+        assert!(s.write_str("ab\u{e9}\u{e9}").is_err());
+        assert_eq!(s.as_str(), "ab\u{e9}");
+    }
+}
@@ -1,29 +1,44 @@
 //! VGA text mode driver for 80x25 display.
 //!
-//! Uses terminal-style coordinates where row 0 is the bottom of the screen.
-//! New text appears at the bottom and scrolls upward as lines are added.
-//! This matches typical terminal behavior (newest content at bottom).
+//! By default uses coordinates where row 0 is the bottom of the screen. New
+//! text appears at the bottom and scrolls upward as lines are added, so the
+//! newest content is always at the bottom. With `ScrollMode::TopDown` row 0
+//! is the top of the screen instead and output starts there like in a
+//! conventional terminal.
 
-use core::ptr::write_volatile;
+use crate::sync::IrqMutex;
+use core::ptr::{read_volatile, write_volatile};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use x86_64::instructions::port::Port;
 
 lazy_static! {
-    pub static ref SCREEN: Mutex<VgaScreen> = {
-        let default_color = ColorCode::new(Color::LightGray, Color::Black);
-        Mutex::new(VgaScreen{
+    pub static ref SCREEN: IrqMutex<VgaScreen> = {
+        // SAFETY: 0xb8000 is identity-mapped by the bootloader and points to
+        // the VGA buffer. We are running in ring0 and have access to the
+        // buffer.
+        let buffer: &'static mut [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] =
+            unsafe { &mut *(0xb8000 as *mut _) };
+        // SAFETY: The buffer is valid for reads and has exactly the size and
+        // layout of the array.
+        let displayed = unsafe { read_volatile(&raw const *buffer) };
+        IrqMutex::new(VgaScreen{
             column: 0,
-            color_code: default_color,
-            // SAFETY: 0xb8000 is identity-mapped by the bootloader and points to
-            // the VGA buffer. We are running in ring0 and have access to the
-            // buffer.
-            buffer: unsafe { &mut *(0xb8000 as *mut _)},
-            shadow: [[ScreenChar{character: b' ', color: default_color}; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            color_code: DEFAULT_COLOR,
+            tab_width: DEFAULT_TAB_WIDTH,
+            ansi: AnsiParser::new(),
+            scrollback: Scrollback::new(),
+            view_offset: 0,
+            scroll_mode: ScrollMode::BottomUp,
+            cursor_row: BUFFER_HEIGHT - 1,
+            saved_position: (BUFFER_HEIGHT - 1, 0),
+            buffer,
+            displayed,
+            shadow: [[ScreenChar{character: b' ', color: DEFAULT_COLOR}; BUFFER_WIDTH]; BUFFER_HEIGHT],
         })
     };
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Color {
     Black = 0,
@@ -44,7 +59,34 @@ pub enum Color {
     White = 15,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl TryFrom<u8> for Color {
+    /// The value outside of 0..=15
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0 => Color::Black,
+            1 => Color::Blue,
+            2 => Color::Green,
+            3 => Color::Cyan,
+            4 => Color::Red,
+            5 => Color::Magenta,
+            6 => Color::Brown,
+            7 => Color::LightGray,
+            8 => Color::DarkGray,
+            9 => Color::LightBlue,
+            10 => Color::LightGreen,
+            11 => Color::LightCyan,
+            12 => Color::LightRed,
+            13 => Color::Pink,
+            14 => Color::Yellow,
+            15 => Color::White,
+            _ => return Err(value),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct ColorCode(u8);
 
@@ -53,9 +95,58 @@ impl ColorCode {
     pub const fn new(foreground: Color, background: Color) -> Self {
         Self((background as u8) << 4 | foreground as u8)
     }
+
+    pub fn foreground(self) -> Color {
+        Color::try_from(self.0 & 0x0f).expect("nibble is a valid color")
+    }
+
+    /// The background color. With blinking enabled the high bit selects
+    /// blinking instead of a bright background.
+    pub fn background(self) -> Color {
+        Color::try_from(self.0 >> 4).expect("nibble is a valid color")
+    }
+
+    /// The raw attribute byte as stored in the VGA buffer.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    const fn with_foreground(self, foreground: Color) -> Self {
+        Self(self.0 & 0xf0 | foreground as u8)
+    }
+
+    const fn with_background(self, background: Color) -> Self {
+        Self((background as u8) << 4 | self.0 & 0x0f)
+    }
+
+    /// Applies a single ANSI Select Graphic Rendition parameter.
+    fn apply_sgr(self, param: u16) -> Self {
+        // ANSI color order: black, red, green, yellow, blue, magenta, cyan, white
+        const ANSI_COLORS: [Color; 8] = [
+            Color::Black,
+            Color::Red,
+            Color::Green,
+            Color::Brown,
+            Color::Blue,
+            Color::Magenta,
+            Color::Cyan,
+            Color::LightGray,
+        ];
+
+        match param {
+            0 => DEFAULT_COLOR,
+            30..=37 => self.with_foreground(ANSI_COLORS[(param - 30) as usize]),
+            39 => self.with_foreground(Color::LightGray),
+            40..=47 => self.with_background(ANSI_COLORS[(param - 40) as usize]),
+            49 => self.with_background(Color::Black),
+            _ => self,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+pub const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::LightGray, Color::Black);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct ScreenChar {
     pub character: u8,
@@ -72,61 +163,501 @@ const _: () = {
 
 pub const BUFFER_HEIGHT: usize = 25;
 pub const BUFFER_WIDTH: usize = 80;
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+pub const SCROLLBACK_LINES: usize = 100;
+
+// CRT controller index/data ports and the registers used for the cursor
+const CRTC_INDEX_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+// Code page 437 double-line box drawing glyphs
+const BOX_TOP_LEFT: u8 = 0xc9;
+const BOX_TOP_RIGHT: u8 = 0xbb;
+const BOX_BOTTOM_LEFT: u8 = 0xc8;
+const BOX_BOTTOM_RIGHT: u8 = 0xbc;
+const BOX_HORIZONTAL: u8 = 0xcd;
+const BOX_VERTICAL: u8 = 0xba;
+
+// Attribute controller ports. The controller has a single port for index and
+// data writes and toggles between them with an internal flip-flop, which a
+// read of the input status register resets to "index".
+const ATTRIBUTE_ADDRESS_DATA_PORT: u16 = 0x3c0;
+const ATTRIBUTE_DATA_READ_PORT: u16 = 0x3c1;
+const INPUT_STATUS_1_PORT: u16 = 0x3da;
+const ATTRIBUTE_MODE_CONTROL: u8 = 0x10;
+// Set in the attribute index to keep the display enabled while programming
+const ATTRIBUTE_PALETTE_ADDRESS_SOURCE: u8 = 1 << 5;
+// Bit 3 of the mode control register: attribute bit 7 blinks instead of
+// selecting a bright background
+const ATTRIBUTE_BLINK_ENABLE: u8 = 1 << 3;
+
+// Bit 5 of the cursor start register disables the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
+// Underline cursor on the last two scanlines of the 16 scanline font
+const CURSOR_SCANLINE_START: u8 = 14;
+const CURSOR_SCANLINE_END: u8 = 15;
+
+/// Where output starts and how row numbers are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScrollMode {
+    /// Row 0 is the bottom line, output is always written there.
+    #[default]
+    BottomUp,
+    /// Row 0 is the top line, output starts there and moves down until the
+    /// last line is reached and the screen scrolls.
+    TopDown,
+}
 
 #[derive(Debug)]
 pub struct VgaScreen {
     column: usize,
     color_code: ColorCode,
+    tab_width: usize,
+    ansi: AnsiParser,
+    scrollback: Scrollback,
+    // Number of lines the view is scrolled back into the scrollback
+    view_offset: usize,
+    scroll_mode: ScrollMode,
+    // Line of the shadow buffer the output is written to, top line is 0
+    cursor_row: usize,
+    // Cursor row and column stored by `ESC [ s`
+    saved_position: (usize, usize),
     buffer: &'static mut [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    // What the last flush left in the VGA buffer
+    displayed: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    // Output is composed here and copied to the VGA buffer by flush
     shadow: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
 impl VgaScreen {
+    /// Copies the cells that changed since the last flush to the VGA buffer.
+    ///
+    /// Writing only the differences keeps scrolling cheap, as a scroll
+    /// changes most cells but whole blank runs stay as they are.
     pub fn flush(&mut self) {
-        // SAFETY: After initialization VgaScreen buffer points to the correct
-        // memory address for the VGA buffer (identify-mapped by the bootloader)
-        // and we have access in ring0. The loop bounds ensure we are within the
-        // bounds of is memory region. Access to the buffer is managed via a
-        // Mutex. The shadow buffer is the same size and type as the buffer.
-        unsafe {
-            write_volatile(self.buffer, self.shadow);
+        let view = self.viewport();
+
+        for (row, line) in view.iter().enumerate() {
+            if *line == self.displayed[row] {
+                continue;
+            }
+            for (col, &cell) in line.iter().enumerate() {
+                if cell != self.displayed[row][col] {
+                    // SAFETY: After initialization VgaScreen buffer points to
+                    // the VGA buffer (identity-mapped by the bootloader) and
+                    // we have access in ring0. row and col are within the
+                    // buffer. Access to the buffer is managed via a Mutex.
+                    unsafe { write_volatile(&raw mut self.buffer[row][col], cell) };
+                }
+            }
+            self.displayed[row] = *line;
         }
     }
 
+    /// Composes the visible lines from the scrollback and the live screen.
+    fn viewport(&self) -> [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] {
+        let mut view = self.shadow;
+        if self.view_offset == 0 {
+            return view;
+        }
+
+        let shift = self.view_offset.min(BUFFER_HEIGHT);
+        view.copy_within(..BUFFER_HEIGHT - shift, shift);
+        let first = self.scrollback.len() - self.view_offset;
+        for (row, line) in view[..shift].iter_mut().enumerate() {
+            *line = *self.scrollback.line(first + row);
+        }
+        view
+    }
+
+    /// Scrolls the view `lines` lines back into the scrollback.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.view_offset = (self.view_offset + lines).min(self.scrollback.len());
+        self.flush();
+    }
+
+    /// Scrolls the view `lines` lines towards the live screen.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.view_offset = self.view_offset.saturating_sub(lines);
+        self.flush();
+    }
+
+    /// Same as `erase_to_line_end`.
     pub fn clear_line(&mut self) {
+        self.erase_to_line_end();
+    }
+
+    /// Fills the output line from the cursor to its end with spaces in the
+    /// current color.
+    pub fn erase_to_line_end(&mut self) {
         for col in self.column..BUFFER_WIDTH {
-            self.write(b' ', self.color_code, 0, col);
+            self.put(b' ', col);
+        }
+    }
+
+    /// Fills the whole output line with spaces in the current color, the
+    /// cursor does not move.
+    pub fn erase_line(&mut self) {
+        for col in 0..BUFFER_WIDTH {
+            self.put(b' ', col);
+        }
+    }
+
+    /// Erases the output line from the cursor and all lines below it.
+    ///
+    /// In `ScrollMode::BottomUp` the output line is the last line, so only
+    /// its tail is erased.
+    pub fn erase_to_screen_end(&mut self) {
+        self.erase_to_line_end();
+        let blank = ScreenChar {
+            character: b' ',
+            color: self.color_code,
+        };
+        for line in &mut self.shadow[self.cursor_row + 1..] {
+            *line = [blank; BUFFER_WIDTH];
+        }
+    }
+
+    pub fn scroll_mode(&self) -> ScrollMode {
+        self.scroll_mode
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Moves the cursor to `col` on the current line. Columns past the end
+    /// are clamped to `BUFFER_WIDTH`, where the next byte wraps.
+    pub fn set_column(&mut self, col: usize) {
+        self.column = col.min(BUFFER_WIDTH);
+    }
+
+    /// Returns the cursor position as `(row, column)`, with the row counted
+    /// as in the current scroll mode like the rows taken by `write`.
+    pub fn position(&self) -> (usize, usize) {
+        // Both modes map rows to lines and back the same way
+        (self.physical_row(self.cursor_row), self.column)
+    }
+
+    /// Switches the coordinate system and clears the screen, so output
+    /// starts at the first line of the new mode.
+    pub fn set_scroll_mode(&mut self, mode: ScrollMode) {
+        self.scroll_mode = mode;
+        self.clear_screen();
+    }
+
+    /// Translates a row of the current scroll mode to a line of the shadow
+    /// buffer, where the top line is 0.
+    fn physical_row(&self, row: usize) -> usize {
+        match self.scroll_mode {
+            ScrollMode::BottomUp => BUFFER_HEIGHT - row - 1,
+            ScrollMode::TopDown => row,
+        }
+    }
+
+    /// Returns the row `lines` lines further down the screen from `row`, if
+    /// it is on the screen.
+    fn row_below(&self, row: usize, lines: usize) -> Option<usize> {
+        match self.scroll_mode {
+            ScrollMode::BottomUp => row.checked_sub(lines),
+            ScrollMode::TopDown => Some(row + lines).filter(|&row| row < BUFFER_HEIGHT),
+        }
+    }
+
+    pub fn set_color(&mut self, color: ColorCode) {
+        self.color_code = color;
+    }
+
+    /// Runs `f` with `color` as the current color and restores the previous
+    /// color afterwards.
+    pub fn with_color<R>(&mut self, color: ColorCode, f: impl FnOnce(&mut Self) -> R) -> R {
+        let previous = self.color_code;
+        self.set_color(color);
+        let result = f(self);
+        self.set_color(previous);
+        result
+    }
+
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.write(b' ', self.color_code, row, col);
+            }
+        }
+        self.column = 0;
+        self.cursor_row = match self.scroll_mode {
+            ScrollMode::BottomUp => BUFFER_HEIGHT - 1,
+            ScrollMode::TopDown => 0,
+        };
+        self.update_cursor();
+        self.flush();
+    }
+
+    /// Writes `s` starting at an absolute position without moving the cursor.
+    ///
+    /// Text past the right edge of the screen is clipped.
+    pub fn write_str_at(&mut self, row: usize, col: usize, s: &str, color: ColorCode) {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            panic!("write access to vga buffer out of bounds");
+        }
+
+        for (col, ch) in (col..BUFFER_WIDTH).zip(s.chars()) {
+            self.write(cp437(ch), color, row, col);
+        }
+        self.flush();
+    }
+
+    /// Writes `s` horizontally centered on `row` without moving the cursor.
+    ///
+    /// Text longer than a line is truncated.
+    pub fn write_centered(&mut self, row: usize, s: &str, color: ColorCode) {
+        let len = s.chars().count().min(BUFFER_WIDTH);
+        self.write_str_at(row, (BUFFER_WIDTH - len) / 2, s, color);
+    }
+
+    /// Writes `s` aligned to the right edge of `row` without moving the
+    /// cursor.
+    ///
+    /// Text longer than a line is truncated.
+    pub fn write_right(&mut self, row: usize, s: &str, color: ColorCode) {
+        let len = s.chars().count().min(BUFFER_WIDTH);
+        // An empty string would start past the last column
+        self.write_str_at(row, (BUFFER_WIDTH - len).min(BUFFER_WIDTH - 1), s, color);
+    }
+
+    /// Draws a double-line box with its top left corner at `top`, `left`
+    /// without moving the cursor.
+    ///
+    /// The box spans `height` lines down the screen and `width` columns.
+    /// Parts beyond the screen edges are clipped and boxes smaller than 2x2
+    /// are not drawn. The inside is left untouched.
+    pub fn draw_box(
+        &mut self,
+        top: usize,
+        left: usize,
+        height: usize,
+        width: usize,
+        color: ColorCode,
+    ) {
+        if top >= BUFFER_HEIGHT || left >= BUFFER_WIDTH {
+            panic!("write access to vga buffer out of bounds");
+        }
+        if height < 2 || width < 2 {
+            return;
+        }
+
+        for line in 0..height {
+            let Some(row) = self.row_below(top, line) else {
+                break;
+            };
+            let first_line = line == 0;
+            let last_line = line == height - 1;
+
+            for (offset, col) in (left..BUFFER_WIDTH).take(width).enumerate() {
+                let first_col = offset == 0;
+                let last_col = offset == width - 1;
+
+                let glyph = match (first_line, last_line, first_col, last_col) {
+                    (true, _, true, _) => BOX_TOP_LEFT,
+                    (true, _, _, true) => BOX_TOP_RIGHT,
+                    (_, true, true, _) => BOX_BOTTOM_LEFT,
+                    (_, true, _, true) => BOX_BOTTOM_RIGHT,
+                    (true, _, _, _) | (_, true, _, _) => BOX_HORIZONTAL,
+                    (_, _, true, _) | (_, _, _, true) => BOX_VERTICAL,
+                    _ => continue,
+                };
+                self.write(glyph, color, row, col);
+            }
         }
+        self.flush();
     }
 
     pub fn new_line(&mut self) {
-        // Move every line up one, top line is lost
-        self.shadow.copy_within(1.., 0);
+        if self.cursor_row < BUFFER_HEIGHT - 1 {
+            self.cursor_row += 1;
+        } else {
+            // Move every line up one, top line goes to the scrollback
+            self.scrollback.push(&self.shadow[0]);
+            self.shadow.copy_within(1.., 0);
+        }
         self.column = 0;
         self.clear_line();
     }
 
     pub fn write_byte(&mut self, byte: u8) {
-        if self.column >= BUFFER_WIDTH {
-            self.new_line();
+        // New output snaps the view back to the live screen
+        self.view_offset = 0;
+
+        match self.ansi.advance(byte) {
+            AnsiAction::Print => {}
+            AnsiAction::Consumed => return,
+            AnsiAction::Dispatch(command) => {
+                self.dispatch_csi(command);
+                self.update_cursor();
+                return;
+            }
         }
 
         if byte == b'\n' {
             self.new_line();
+        } else if byte == b'\r' {
+            // Return to the start of the line, the next write overwrites it
+            self.column = 0;
+        } else if byte == b'\t' {
+            self.tab();
         } else {
-            self.write(byte, self.color_code, 0, self.column);
+            self.put_run(&[byte]);
+        }
+
+        self.update_cursor();
+    }
+
+    /// Writes `bytes` like calling `write_byte` for each of them, but copies
+    /// runs of printable bytes into the output line at once.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        // New output snaps the view back to the live screen
+        self.view_offset = 0;
+
+        let mut rest = bytes;
+        while let Some(&first) = rest.first() {
+            if self.ansi.is_ground() {
+                let run = rest
+                    .iter()
+                    .position(|&byte| matches!(byte, b'\n' | b'\r' | b'\t' | ESC))
+                    .unwrap_or(rest.len());
+                if run > 0 {
+                    self.put_run(&rest[..run]);
+                    rest = &rest[run..];
+                    continue;
+                }
+            }
+            self.write_byte(first);
+            rest = &rest[1..];
+        }
+
+        self.update_cursor();
+    }
+
+    /// Writes printable bytes to the output line and advances the cursor,
+    /// wrapping to the next line as needed. Both `write_byte` and
+    /// `write_bytes` print through here.
+    fn put_run(&mut self, mut run: &[u8]) {
+        while !run.is_empty() {
+            // A full line wraps only once the next character arrives, so a
+            // line of exactly BUFFER_WIDTH characters and its newline don't
+            // leave a blank line
+            if self.column >= BUFFER_WIDTH {
+                self.new_line();
+            }
+
+            let len = run.len().min(BUFFER_WIDTH - self.column);
+            for (offset, &byte) in run[..len].iter().enumerate() {
+                self.put(byte, self.column + offset);
+            }
+            self.column += len;
+            run = &run[len..];
+        }
+    }
+
+    /// Executes a complete CSI sequence, unsupported commands are ignored.
+    fn dispatch_csi(&mut self, command: u8) {
+        let mode = self.ansi.params()[0];
+        let count = usize::from(mode.max(1));
+        match command {
+            b'm' => {
+                self.color_code = self
+                    .ansi
+                    .params()
+                    .iter()
+                    .fold(self.color_code, |color, &param| color.apply_sgr(param));
+            }
+            // Erase in line
+            b'K' if mode == 0 => self.erase_to_line_end(),
+            b'K' if mode == 2 => self.erase_line(),
+            // Erase in display
+            b'J' if mode == 0 => self.erase_to_screen_end(),
+            b'J' if mode == 2 => self.clear_screen(),
+            // Cursor position, 1-based from the top left corner
+            b'H' | b'f' => {
+                let params = self.ansi.params();
+                let row = usize::from(params[0]).clamp(1, BUFFER_HEIGHT);
+                let col = usize::from(params.get(1).copied().unwrap_or(0)).clamp(1, BUFFER_WIDTH);
+                self.cursor_row = row - 1;
+                self.column = col - 1;
+            }
+            // Cursor up, down, forward and back, by at least one
+            b'A' => self.cursor_row = self.cursor_row.saturating_sub(count),
+            b'B' => self.cursor_row = (self.cursor_row + count).min(BUFFER_HEIGHT - 1),
+            b'C' => self.column = (self.column + count).min(BUFFER_WIDTH - 1),
+            b'D' => self.column = self.column.min(BUFFER_WIDTH - 1).saturating_sub(count),
+            b's' => self.saved_position = (self.cursor_row, self.column),
+            b'u' => (self.cursor_row, self.column) = self.saved_position,
+            _ => {}
+        }
+    }
+
+    /// Advances to the next tab stop, filling the skipped cells with spaces.
+    fn tab(&mut self) {
+        let next_stop = (self.column / self.tab_width + 1) * self.tab_width;
+        if next_stop > BUFFER_WIDTH {
+            self.new_line();
+            return;
+        }
+
+        while self.column < next_stop {
+            self.put(b' ', self.column);
             self.column += 1;
         }
     }
 
+    /// Sets the distance between tab stops, a width of 0 is treated as 1.
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width.max(1);
+    }
+
+    /// Moves the blinking hardware cursor to the current write position.
+    pub fn update_cursor(&mut self) {
+        let row = self.cursor_row;
+        // After the last column the cursor stays on the last cell until the
+        // next write wraps to a new line.
+        let col = self.column.min(BUFFER_WIDTH - 1);
+        let position = (row * BUFFER_WIDTH + col) as u16;
+
+        crtc_write(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        crtc_write(CRTC_CURSOR_LOCATION_LOW, position as u8);
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        if visible {
+            crtc_write(CRTC_CURSOR_START, CURSOR_SCANLINE_START);
+            crtc_write(CRTC_CURSOR_END, CURSOR_SCANLINE_END);
+        } else {
+            crtc_write(CRTC_CURSOR_START, CURSOR_DISABLE);
+        }
+    }
+
+    /// Selects whether the high bit of the background makes text blink or
+    /// selects one of the bright background colors 8..=15.
+    pub fn set_blink_enabled(&mut self, enabled: bool) {
+        let mode = attribute_read(ATTRIBUTE_MODE_CONTROL);
+        let mode = if enabled {
+            mode | ATTRIBUTE_BLINK_ENABLE
+        } else {
+            mode & !ATTRIBUTE_BLINK_ENABLE
+        };
+        attribute_write(ATTRIBUTE_MODE_CONTROL, mode);
+    }
+
     pub fn write(&mut self, byte: u8, color: ColorCode, row: usize, col: usize) {
         if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
             panic!("write access to vga buffer out of bounds");
         }
 
-        // Writing starts from the bottom left of the screen
-        let row = BUFFER_HEIGHT - row - 1;
-
+        let row = self.physical_row(row);
         let ch = ScreenChar {
             character: byte,
             color,
@@ -135,28 +666,318 @@ impl VgaScreen {
         self.shadow[row][col] = ch;
     }
 
+    /// Writes `byte` in the current color to the output line.
+    fn put(&mut self, byte: u8, col: usize) {
+        self.shadow[self.cursor_row][col] = ScreenChar {
+            character: byte,
+            color: self.color_code,
+        };
+    }
+
+    /// Returns a copy of what is currently displayed, top line first.
+    pub fn snapshot(&self) -> [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] {
+        // SAFETY: The buffer points to the identity-mapped VGA buffer (see
+        // flush) and has exactly the size and layout of the returned array.
+        unsafe { read_volatile(&raw const *self.buffer) }
+    }
+
+    /// Returns the displayed characters of `row`, counted as in the current
+    /// scroll mode.
+    pub fn row_text(&self, row: usize) -> [u8; BUFFER_WIDTH] {
+        if row >= BUFFER_HEIGHT {
+            panic!("read access to vga buffer out of bounds");
+        }
+
+        let line = &self.snapshot()[self.physical_row(row)];
+        core::array::from_fn(|col| line[col].character)
+    }
+
+    /// Returns a 64 bit FNV-1a hash of the displayed characters and colors,
+    /// for tests comparing whole screens.
+    pub fn checksum(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+        self.snapshot()
+            .iter()
+            .flatten()
+            .flat_map(|cell| [cell.character, cell.color.bits()])
+            .fold(FNV_OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// Panics unless the displayed `row` starts with `expected`, naming the
+    /// first differing column. Rows are counted as in the current scroll
+    /// mode.
     #[cfg(test)]
-    fn read(&self, row: usize, col: usize) -> ScreenChar {
+    #[track_caller]
+    pub(crate) fn assert_text(&self, row: usize, expected: &str) {
+        let actual = self.row_text(row);
+        let expected = expected.as_bytes();
+        assert!(
+            expected.len() <= BUFFER_WIDTH,
+            "expected text is wider than the screen"
+        );
+
+        let found = &actual[..expected.len()];
+        if let Some(col) = found.iter().zip(expected).position(|(a, e)| a != e) {
+            panic!(
+                "screen row {} differs at column {}:\n expected: \"{}\"\n    found: \"{}\"",
+                row,
+                col,
+                expected.escape_ascii(),
+                found.escape_ascii()
+            );
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn read(&self, row: usize, col: usize) -> ScreenChar {
         if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
             panic!("read access to vga buffer out of bounds");
         }
 
-        // Reading starts from the bottom left of the screen to match writing
-        let row = BUFFER_HEIGHT - row - 1;
+        self.shadow[self.physical_row(row)][col]
+    }
+}
+
+/// Ring buffer of the lines scrolled off the top of the screen.
+#[derive(Debug)]
+struct Scrollback {
+    lines: [[ScreenChar; BUFFER_WIDTH]; SCROLLBACK_LINES],
+    // Index of the oldest line
+    start: usize,
+    len: usize,
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        let blank = ScreenChar {
+            character: b' ',
+            color: DEFAULT_COLOR,
+        };
+        Self {
+            lines: [[blank; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Appends a line, dropping the oldest one if the buffer is full.
+    fn push(&mut self, line: &[ScreenChar; BUFFER_WIDTH]) {
+        if self.len < SCROLLBACK_LINES {
+            self.lines[(self.start + self.len) % SCROLLBACK_LINES] = *line;
+            self.len += 1;
+        } else {
+            self.lines[self.start] = *line;
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    /// Returns the line at `index` where 0 is the oldest line.
+    fn line(&self, index: usize) -> &[ScreenChar; BUFFER_WIDTH] {
+        assert!(index < self.len, "scrollback index out of bounds");
+        &self.lines[(self.start + index) % SCROLLBACK_LINES]
+    }
+}
+
+const ESC: u8 = 0x1b;
+const MAX_ANSI_PARAMS: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+enum AnsiState {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// What the writer should do with a byte fed through the ANSI parser.
+#[derive(Debug, Clone, Copy)]
+enum AnsiAction {
+    /// Not part of an escape sequence, print it
+    Print,
+    /// Swallowed as part of an (incomplete or unsupported) escape sequence
+    Consumed,
+    /// A CSI sequence completed with the given final byte
+    Dispatch(u8),
+}
+
+/// Recognizes `ESC [ <params> <final>` (CSI) sequences byte by byte.
+///
+/// Parameters are decimal numbers separated by `;`, missing numbers read as 0
+/// and parameters beyond `MAX_ANSI_PARAMS` are dropped. Escape sequences that
+/// are not CSI sequences are swallowed.
+#[derive(Debug)]
+struct AnsiParser {
+    state: AnsiState,
+    params: [u16; MAX_ANSI_PARAMS],
+    // Index of the parameter currently being parsed
+    current: usize,
+}
+
+impl AnsiParser {
+    const fn new() -> Self {
+        Self {
+            state: AnsiState::Ground,
+            params: [0; MAX_ANSI_PARAMS],
+            current: 0,
+        }
+    }
+
+    /// Returns whether no escape sequence is in progress.
+    fn is_ground(&self) -> bool {
+        matches!(self.state, AnsiState::Ground)
+    }
+
+    fn advance(&mut self, byte: u8) -> AnsiAction {
+        match self.state {
+            AnsiState::Ground if byte == ESC => {
+                self.state = AnsiState::Escape;
+                AnsiAction::Consumed
+            }
+            AnsiState::Ground => AnsiAction::Print,
+            AnsiState::Escape if byte == b'[' => {
+                self.state = AnsiState::Csi;
+                self.params = [0; MAX_ANSI_PARAMS];
+                self.current = 0;
+                AnsiAction::Consumed
+            }
+            AnsiState::Escape => {
+                self.state = AnsiState::Ground;
+                AnsiAction::Consumed
+            }
+            AnsiState::Csi => match byte {
+                b'0'..=b'9' => {
+                    if let Some(param) = self.params.get_mut(self.current) {
+                        *param = param
+                            .saturating_mul(10)
+                            .saturating_add(u16::from(byte - b'0'));
+                    }
+                    AnsiAction::Consumed
+                }
+                b';' => {
+                    self.current = self.current.saturating_add(1);
+                    AnsiAction::Consumed
+                }
+                // Intermediate and private parameter bytes, ignored
+                0x20..=0x3f => AnsiAction::Consumed,
+                0x40..=0x7e => {
+                    self.state = AnsiState::Ground;
+                    AnsiAction::Dispatch(byte)
+                }
+                // Anything else is malformed, drop the whole sequence
+                _ => {
+                    self.state = AnsiState::Ground;
+                    AnsiAction::Consumed
+                }
+            },
+        }
+    }
+
+    /// Parameters of the most recently dispatched sequence.
+    fn params(&self) -> &[u16] {
+        &self.params[..(self.current + 1).min(MAX_ANSI_PARAMS)]
+    }
+}
+
+fn crtc_write(index: u8, value: u8) {
+    let mut index_port = Port::new(CRTC_INDEX_PORT);
+    let mut data_port = Port::new(CRTC_DATA_PORT);
+
+    // SAFETY: 0x3d4/0x3d5 are the CRT controller ports of the color VGA
+    // adapter. We are running in ring 0 and only write the cursor registers
+    // which have no side effects on memory. Callers hold the SCREEN lock so the
+    // index/data write pair cannot be interleaved.
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+/// Resets the attribute controller flip-flop so the next write to 0x3c0 is
+/// taken as an index.
+fn attribute_reset_flip_flop() {
+    let mut status_port: Port<u8> = Port::new(INPUT_STATUS_1_PORT);
+
+    // SAFETY: 0x3da is the input status register of the color VGA adapter.
+    // Reading it only resets the attribute controller flip-flop.
+    unsafe {
+        status_port.read();
+    }
+}
+
+fn attribute_read(index: u8) -> u8 {
+    let mut address_port = Port::new(ATTRIBUTE_ADDRESS_DATA_PORT);
+    let mut data_port = Port::new(ATTRIBUTE_DATA_READ_PORT);
 
-        self.shadow[row][col]
+    attribute_reset_flip_flop();
+    // SAFETY: 0x3c0/0x3c1 are the attribute controller ports of the VGA
+    // adapter and the flip-flop was reset to expect an index. Callers hold
+    // the SCREEN lock so the sequence cannot be interleaved.
+    unsafe {
+        address_port.write(index | ATTRIBUTE_PALETTE_ADDRESS_SOURCE);
+        data_port.read()
     }
 }
 
+fn attribute_write(index: u8, value: u8) {
+    let mut address_data_port = Port::new(ATTRIBUTE_ADDRESS_DATA_PORT);
+
+    attribute_reset_flip_flop();
+    // SAFETY: 0x3c0 is the attribute controller port of the VGA adapter,
+    // taking the index and then the value after the flip-flop reset. Only
+    // display attributes change. Callers hold the SCREEN lock so the
+    // sequence cannot be interleaved.
+    unsafe {
+        address_data_port.write(index | ATTRIBUTE_PALETTE_ADDRESS_SOURCE);
+        address_data_port.write(value);
+    }
+}
+
+/// Block character printed for chars without a code page 437 glyph
+const UNMAPPED_GLYPH: u8 = 0xfe;
+
+/// Unicode chars for the code page 437 glyphs 0x80..=0xff
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Translates a char to its code page 437 byte as used by the VGA font.
+fn cp437(c: char) -> u8 {
+    if c.is_ascii() {
+        return c as u8;
+    }
+
+    CP437_HIGH
+        .iter()
+        .position(|&glyph| glyph == c)
+        .map_or(UNMAPPED_GLYPH, |index| 0x80 + index as u8)
+}
+
 impl core::fmt::Write for VgaScreen {
-    // Only ASCII will be printed properly on the VGA screen
+    // Chars without a code page 437 glyph are printed as a block
     fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
+        // ASCII is the same in code page 437
+        if s.is_ascii() {
+            self.write_bytes(s.as_bytes());
+            return Ok(());
+        }
+
         for ch in s.chars() {
-            if ch.is_ascii() {
-                self.write_byte(ch as u8);
-            } else {
-                self.write_byte(0xFE); // write the block char if not ASCII
-            }
+            self.write_byte(cp437(ch));
         }
         Ok(())
     }
@@ -173,15 +994,65 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+#[macro_export]
+macro_rules! cprint {
+    ($fg:expr, $bg:expr, $($arg:tt)*) => (
+        $crate::vga::_cprint($crate::vga::ColorCode::new($fg, $bg), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! cprintln {
+    ($fg:expr, $bg:expr) => ($crate::cprint!($fg, $bg, "\n"));
+    ($fg:expr, $bg:expr, $($arg:tt)*) => ($crate::cprint!($fg, $bg, "{}\n", format_args!($($arg)*)));
+}
+
+/// Devices `print!` and `println!` write to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputSink {
+    #[default]
+    Vga,
+    Serial,
+    Both,
+}
+
+static OUTPUT_SINK: IrqMutex<OutputSink> = IrqMutex::new(OutputSink::Vga);
+
+/// Routes `print!` and `println!` output to `sink`.
+///
+/// `cprint!` keeps writing to the screen only, as serial has no colors.
+pub fn set_output_sink(sink: OutputSink) {
+    *OUTPUT_SINK.lock() = sink;
+}
+
+pub fn output_sink() -> OutputSink {
+    *OUTPUT_SINK.lock()
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(|| {
-        let mut vga = SCREEN.lock();
-        vga.write_fmt(args).expect("VGA write failed");
-        vga.flush();
-    });
+
+    match output_sink() {
+        OutputSink::Vga => {
+            let mut vga = SCREEN.lock();
+            vga.write_fmt(args).expect("VGA write failed");
+            vga.flush();
+        }
+        OutputSink::Serial => crate::serial::_print(args),
+        OutputSink::Both => crate::klog::_log(args),
+    }
+}
+
+#[doc(hidden)]
+pub fn _cprint(color: ColorCode, args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    // The lock is held across the color change and the write so no other
+    // print can observe or interleave with the temporary color.
+    let mut vga = SCREEN.lock();
+    vga.with_color(color, |vga| vga.write_fmt(args))
+        .expect("VGA write failed");
+    vga.flush();
 }
 
 #[cfg(test)]
@@ -213,10 +1084,395 @@ mod tests {
         let s = "Some test string that fits on a single line";
         println!("{}", s);
 
+        SCREEN.lock().assert_text(1, s);
+    }
+
+    #[test_case]
+    fn test_output_sink_serial_skips_screen() {
+        let s = "test_output_sink_serial output";
+        set_output_sink(OutputSink::Serial);
+        println!("{}", s);
+        set_output_sink(OutputSink::Vga);
+
+        assert!(!SCREEN.lock().row_text(1).starts_with(s.as_bytes()));
+
+        set_output_sink(OutputSink::Both);
+        println!("{}", s);
+        set_output_sink(OutputSink::Vga);
+
+        assert!(SCREEN.lock().row_text(1).starts_with(s.as_bytes()));
+    }
+
+    #[test_case]
+    fn test_color_round_trip() {
+        for value in 0..16 {
+            let color = Color::try_from(value).expect("valid color");
+            assert_eq!(color as u8, value);
+        }
+        assert_eq!(Color::try_from(16), Err(16));
+
+        let code = ColorCode::new(Color::Yellow, Color::Blue);
+        assert_eq!(code.foreground(), Color::Yellow);
+        assert_eq!(code.background(), Color::Blue);
+        assert_eq!(code.bits(), 0x1e);
+    }
+
+    #[test_case]
+    fn test_set_blink_enabled() {
+        let mut screen = SCREEN.lock();
+        screen.set_blink_enabled(false);
+        assert_eq!(
+            attribute_read(ATTRIBUTE_MODE_CONTROL) & ATTRIBUTE_BLINK_ENABLE,
+            0
+        );
+        screen.set_blink_enabled(true);
+        assert_ne!(
+            attribute_read(ATTRIBUTE_MODE_CONTROL) & ATTRIBUTE_BLINK_ENABLE,
+            0
+        );
+    }
+
+    #[test_case]
+    fn test_with_color() {
+        let mut screen = SCREEN.lock();
+        let previous = screen.color_code;
+        let color = ColorCode::new(Color::Red, Color::Black);
+
+        screen.with_color(color, |screen| screen.write_byte(b'X'));
+
+        let column = screen.column - 1;
+        assert_eq!(screen.read(0, column).color, color);
+        assert_eq!(screen.color_code, previous);
+    }
+
+    #[test_case]
+    fn test_cprintln_restores_color() {
+        let previous = SCREEN.lock().color_code;
+        cprintln!(Color::Green, Color::Black, "test_cprintln output");
+
         let screen = SCREEN.lock();
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = screen.read(1, i);
-            assert_eq!(char::from(screen_char.character) as u8, c as u8);
+        let color = ColorCode::new(Color::Green, Color::Black);
+        assert_eq!(screen.read(1, 0).color, color);
+        assert_eq!(screen.color_code, previous);
+    }
+
+    #[test_case]
+    fn test_carriage_return() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        write!(screen, "\nhello\rHI").unwrap();
+
+        let line: [u8; 5] = core::array::from_fn(|col| screen.read(0, col).character);
+        assert_eq!(&line, b"HIllo");
+    }
+
+    #[test_case]
+    fn test_tab_expansion() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        write!(screen, "\nabc\t").unwrap();
+
+        assert_eq!(screen.column, 8);
+        for col in 3..8 {
+            assert_eq!(screen.read(0, col).character, b' ');
+        }
+    }
+
+    #[test_case]
+    fn test_ansi_sgr_colors() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        let previous = screen.color_code;
+        write!(screen, "\n\x1b[31mERR\x1b[0m").unwrap();
+
+        let red = DEFAULT_COLOR.with_foreground(Color::Red);
+        for col in 0..3 {
+            assert_eq!(screen.read(0, col).color, red);
+        }
+        assert_eq!(screen.column, 3);
+        assert_eq!(screen.color_code, DEFAULT_COLOR);
+        screen.set_color(previous);
+    }
+
+    #[test_case]
+    fn test_ansi_erase() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        write!(screen, "\nabcdef").unwrap();
+        screen.column = 3;
+        write!(screen, "\x1b[K").unwrap();
+        assert_eq!(screen.read(0, 2).character, b'c');
+        assert_eq!(screen.read(0, 3).character, b' ');
+        assert_eq!(screen.read(0, 5).character, b' ');
+
+        write!(screen, "\x1b[2K").unwrap();
+        assert_eq!(screen.read(0, 0).character, b' ');
+        assert_eq!(screen.column, 3);
+    }
+
+    #[test_case]
+    fn test_write_bytes_matches_write_byte() {
+        let input = b"\nab\tc\x1b[31md\x1b[0m\r";
+        let mut screen = SCREEN.lock();
+
+        for &byte in input {
+            screen.write_byte(byte);
         }
+        let expected = screen.shadow[BUFFER_HEIGHT - 1];
+        let expected_column = screen.column;
+
+        screen.write_bytes(input);
+        assert_eq!(screen.column, expected_column);
+        for (col, expected) in expected.iter().enumerate() {
+            let cell = screen.read(0, col);
+            assert_eq!(cell.character, expected.character);
+            assert_eq!(cell.color, expected.color);
+        }
+    }
+
+    #[test_case]
+    fn test_write_bytes_wraps() {
+        let mut screen = SCREEN.lock();
+        screen.write_byte(b'\n');
+        screen.write_bytes(&[b'x'; BUFFER_WIDTH + 20]);
+
+        assert!(screen.row_text(1).iter().all(|&c| c == b'x'));
+        assert_eq!(screen.column, 20);
+    }
+
+    #[test_case]
+    fn test_cp437_translation() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        write!(screen, "\n│é€").unwrap();
+
+        assert_eq!(screen.read(0, 0).character, 0xb3);
+        assert_eq!(screen.read(0, 1).character, 0x82);
+        assert_eq!(screen.read(0, 2).character, UNMAPPED_GLYPH);
+    }
+
+    #[test_case]
+    fn test_scrollback() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        write!(screen, "\nscrollback marker").unwrap();
+        // Push the marker line off the top of the screen
+        for _ in 0..BUFFER_HEIGHT {
+            screen.new_line();
+        }
+
+        screen.scroll_up(1);
+        assert_eq!(screen.viewport()[0][0].character, b's');
+
+        screen.scroll_down(1);
+        assert_eq!(screen.view_offset, 0);
+
+        screen.scroll_up(2);
+        screen.write_byte(b'x');
+        assert_eq!(screen.view_offset, 0);
+    }
+
+    #[test_case]
+    fn test_write_str_at() {
+        let mut screen = SCREEN.lock();
+        let column = screen.column;
+        let color = ColorCode::new(Color::Yellow, Color::Blue);
+
+        screen.write_str_at(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 6, "status bar", color);
+
+        for (i, c) in b"status".iter().enumerate() {
+            let screen_char = screen.read(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 6 + i);
+            assert_eq!(screen_char.character, *c);
+            assert_eq!(screen_char.color, color);
+        }
+        assert_eq!(screen.column, column);
+    }
+
+    #[test_case]
+    fn test_write_centered_and_right() {
+        let mut screen = SCREEN.lock();
+        let column = screen.column;
+
+        screen.write_centered(12, "abcd", DEFAULT_COLOR);
+        assert_eq!(screen.read(12, 38).character, b'a');
+        assert_eq!(screen.read(12, 41).character, b'd');
+
+        screen.write_right(11, "abcd", DEFAULT_COLOR);
+        assert_eq!(screen.read(11, BUFFER_WIDTH - 4).character, b'a');
+        assert_eq!(screen.read(11, BUFFER_WIDTH - 1).character, b'd');
+        assert_eq!(screen.column, column);
+    }
+
+    #[test_case]
+    fn test_draw_box() {
+        let mut screen = SCREEN.lock();
+        let column = screen.column;
+        let color = ColorCode::new(Color::White, Color::Blue);
+
+        screen.draw_box(10, 5, 4, 6, color);
+
+        assert_eq!(screen.read(10, 5).character, BOX_TOP_LEFT);
+        assert_eq!(screen.read(10, 10).character, BOX_TOP_RIGHT);
+        assert_eq!(screen.read(7, 5).character, BOX_BOTTOM_LEFT);
+        assert_eq!(screen.read(7, 10).character, BOX_BOTTOM_RIGHT);
+        assert_eq!(screen.read(10, 7).character, BOX_HORIZONTAL);
+        assert_eq!(screen.read(8, 5).character, BOX_VERTICAL);
+        assert_eq!(screen.read(10, 5).color, color);
+        assert_eq!(screen.column, column);
+
+        // Clipped at the right and bottom edge
+        screen.draw_box(1, BUFFER_WIDTH - 2, 4, 6, color);
+        assert_eq!(screen.read(1, BUFFER_WIDTH - 2).character, BOX_TOP_LEFT);
+        assert_eq!(screen.read(1, BUFFER_WIDTH - 1).character, BOX_HORIZONTAL);
+        assert_eq!(screen.read(0, BUFFER_WIDTH - 2).character, BOX_VERTICAL);
+    }
+
+    #[test_case]
+    fn test_scroll_mode_top_down() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        screen.set_scroll_mode(ScrollMode::TopDown);
+        write!(screen, "ab\ncd").expect("VGA write failed");
+        assert_eq!(screen.read(0, 0).character, b'a');
+        assert_eq!(screen.read(1, 0).character, b'c');
+        assert_eq!(screen.cursor_row, 1);
+
+        // Once the last line is reached the screen scrolls
+        for line in 0..BUFFER_HEIGHT {
+            write!(screen, "\n{}", line % 10).expect("VGA write failed");
+        }
+        assert_eq!(screen.cursor_row, BUFFER_HEIGHT - 1);
+        assert_eq!(screen.read(BUFFER_HEIGHT - 1, 0).character, b'4');
+        assert_eq!(screen.read(0, 0).character, b'0');
+
+        screen.set_scroll_mode(ScrollMode::BottomUp);
+        assert_eq!(screen.cursor_row, BUFFER_HEIGHT - 1);
+    }
+
+    #[test_case]
+    fn test_clear_screen() {
+        println!("test_clear_screen output");
+
+        let mut screen = SCREEN.lock();
+        screen.clear_screen();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(screen.read(row, col).character, b' ');
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_cursor_position() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        screen.clear_screen();
+        screen.set_column(7);
+        assert_eq!(screen.column(), 7);
+        assert_eq!(screen.position(), (0, 7));
+        write!(screen, "z").expect("VGA write failed");
+        let (row, col) = screen.position();
+        assert_eq!(screen.read(row, col - 1).character, b'z');
+
+        screen.set_column(BUFFER_WIDTH + 5);
+        assert_eq!(screen.column(), BUFFER_WIDTH);
+
+        screen.set_scroll_mode(ScrollMode::TopDown);
+        write!(screen, "ab\ncd").expect("VGA write failed");
+        assert_eq!(screen.position(), (1, 2));
+        screen.set_scroll_mode(ScrollMode::BottomUp);
+    }
+
+    #[test_case]
+    fn test_flush_writes_changed_cells_only() {
+        let mut screen = SCREEN.lock();
+        screen.clear_screen();
+        let marker = ScreenChar {
+            character: b'#',
+            color: DEFAULT_COLOR,
+        };
+        // A cell changed behind the screen's back stays until the shadow
+        // buffer changes it
+        // SAFETY: The buffer is the identity-mapped VGA buffer and the
+        // screen lock is held.
+        unsafe { write_volatile(&raw mut screen.buffer[0][0], marker) };
+        screen.flush();
+        assert_eq!(screen.snapshot()[0][0], marker);
+
+        screen.shadow[0][0].character = b'a';
+        screen.flush();
+        assert_eq!(screen.snapshot()[0][0].character, b'a');
+    }
+
+    #[test_case]
+    fn test_ansi_cursor_movement() {
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        screen.clear_screen();
+        write!(screen, "\x1b[3;5Hx").expect("VGA write failed");
+        assert_eq!(screen.shadow[2][4].character, b'x');
+        assert_eq!(screen.position(), (BUFFER_HEIGHT - 3, 5));
+
+        write!(screen, "\x1b[s\x1b[A\x1b[2Cy").expect("VGA write failed");
+        assert_eq!(screen.shadow[1][7].character, b'y');
+        write!(screen, "\x1b[u\x1b[Bz").expect("VGA write failed");
+        assert_eq!(screen.shadow[3][5].character, b'z');
+
+        // Out of range and missing coordinates are clamped
+        write!(screen, "\x1b[99;999H").expect("VGA write failed");
+        assert_eq!(screen.position(), (0, BUFFER_WIDTH - 1));
+        write!(screen, "\x1b[H\x1b[9D\x1b[9A").expect("VGA write failed");
+        assert_eq!(screen.position(), (BUFFER_HEIGHT - 1, 0));
+        screen.clear_screen();
+    }
+
+    #[test_case]
+    fn test_checksum_tracks_screen() {
+        let mut screen = SCREEN.lock();
+        screen.clear_screen();
+        screen.flush();
+        let blank = screen.checksum();
+
+        screen.write_str_at(3, 0, "checksum", DEFAULT_COLOR);
+        let text = screen.checksum();
+        assert_ne!(text, blank);
+        screen.assert_text(3, "checksum");
+
+        screen.clear_screen();
+        screen.flush();
+        assert_eq!(screen.checksum(), blank);
+    }
+
+    #[test_case]
+    fn test_full_line_wraps_on_next_character() {
+        let full_line = [b'w'; BUFFER_WIDTH];
+        let mut screen = SCREEN.lock();
+        screen.set_scroll_mode(ScrollMode::TopDown);
+
+        screen.write_bytes(&full_line);
+        assert_eq!(screen.position(), (0, BUFFER_WIDTH));
+        // The newline ends the full line without an extra blank one
+        screen.write_bytes(b"\nx");
+        assert_eq!(screen.read(1, 0).character, b'x');
+
+        screen.write_bytes(b"\n");
+        for &byte in &full_line {
+            screen.write_byte(byte);
+        }
+        assert_eq!(screen.position(), (2, BUFFER_WIDTH));
+        screen.write_byte(b'y');
+        assert_eq!(screen.read(3, 0).character, b'y');
+        assert_eq!(screen.position(), (3, 1));
+
+        screen.set_scroll_mode(ScrollMode::BottomUp);
     }
 }
@@ -6,14 +6,14 @@
 
 use bootloader::entry_point;
 use core::panic::PanicInfo;
-use kleinos::{hlt_loop, serial};
+use kleinos::{idle_loop, serial};
 
 entry_point!(test_kernel_main);
 
 fn test_kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
-    serial::SERIAL1.lock().init();
+    serial::init();
     test_main();
-    hlt_loop();
+    idle_loop();
 }
 
 #[panic_handler]
@@ -26,3 +26,14 @@ fn test_println() {
     use kleinos::println;
     println!("test_println output");
 }
+
+#[test_case]
+fn test_println_visible_on_screen() {
+    use kleinos::{println, vga::SCREEN};
+
+    let s = "test_println_visible_on_screen output";
+    println!("{}", s);
+
+    let line = SCREEN.lock().row_text(1);
+    assert_eq!(&line[..s.len()], s.as_bytes());
+}
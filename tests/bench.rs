@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kleinos::bench::bench_runner)]
+#![reexport_test_harness_main = "bench_main"]
+
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use kleinos::{idle_loop, serial, sync::IrqMutex, vga::SCREEN};
+
+entry_point!(bench_kernel_main);
+
+fn bench_kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
+    serial::init();
+    kleinos::init();
+    bench_main();
+    idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kleinos::test_panic_handler(info);
+}
+
+static COUNTER: IrqMutex<u64> = IrqMutex::new(0);
+
+#[test_case]
+fn bench_irq_mutex_lock() {
+    *COUNTER.lock() += 1;
+}
+
+#[test_case]
+fn bench_spin_mutex_lock() {
+    static SPIN_COUNTER: spin::Mutex<u64> = spin::Mutex::new(0);
+    *SPIN_COUNTER.lock() += 1;
+}
+
+#[test_case]
+fn bench_vga_new_line() {
+    SCREEN.lock().new_line();
+}
+
+#[test_case]
+fn bench_vga_write_byte_line() {
+    let mut screen = SCREEN.lock();
+    for _ in 0..80 {
+        screen.write_byte(b'x');
+    }
+}
+
+#[test_case]
+fn bench_vga_write_bytes_line() {
+    SCREEN.lock().write_bytes(&[b'x'; 80]);
+}
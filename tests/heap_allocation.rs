@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kleinos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use kleinos::{idle_loop, memory::heap::HEAP_SIZE, serial};
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
+    serial::init();
+    kleinos::init();
+    test_main();
+    idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kleinos::test_panic_handler(info);
+}
+
+#[test_case]
+fn test_simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn test_large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn test_many_boxes() {
+    // Only works if freed memory is reused
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
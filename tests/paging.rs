@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kleinos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kleinos::{
+    idle_loop,
+    memory::{self, BootInfoFrameAllocator},
+    serial,
+};
+use spin::{Mutex, Once};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{FrameAllocator, OffsetPageTable, Page, PageTableFlags},
+};
+
+static MEMORY: Once<Mutex<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Once::new();
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    serial::init();
+    kleinos::init();
+
+    // SAFETY: The bootloader maps all physical memory at the offset and
+    // marks only unused memory as usable. Both are created only once.
+    let (mapper, frame_allocator) = unsafe {
+        (
+            memory::init(VirtAddr::new(boot_info.physical_memory_offset)),
+            BootInfoFrameAllocator::init(&boot_info.memory_map),
+        )
+    };
+    MEMORY.call_once(|| Mutex::new((mapper, frame_allocator)));
+
+    test_main();
+    idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kleinos::test_panic_handler(info);
+}
+
+#[test_case]
+fn test_translate_vga_buffer() {
+    let (mapper, _) = &*MEMORY.get().expect("memory not initialized").lock();
+    assert_eq!(
+        memory::translate_addr(mapper, VirtAddr::new(0xb8000)),
+        Some(PhysAddr::new(0xb8000))
+    );
+}
+
+#[test_case]
+fn test_map_page() {
+    let (mapper, frame_allocator) = &mut *MEMORY.get().expect("memory not initialized").lock();
+    let page = Page::containing_address(VirtAddr::new(0xdead_beaf_0000));
+    let frame = frame_allocator.allocate_frame().expect("out of frames");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // SAFETY: The frame was just allocated and is not used elsewhere.
+    unsafe { memory::map_page(mapper, page, frame, flags, frame_allocator) }
+        .expect("map_page failed");
+    assert_eq!(
+        memory::translate_addr(mapper, page.start_address()),
+        Some(frame.start_address())
+    );
+
+    let ptr = page.start_address().as_mut_ptr::<u64>();
+    // SAFETY: The page was just mapped writable to an unused frame.
+    unsafe {
+        ptr.write_volatile(0xf021_f077_f065_f04e);
+        assert_eq!(ptr.read_volatile(), 0xf021_f077_f065_f04e);
+    }
+}
+
+#[test_case]
+fn test_stack_guard_below_stack() {
+    let (mapper, _) = &*MEMORY.get().expect("memory not initialized").lock();
+    let guard = memory::setup_stack_guard(mapper).expect("no stack guard page");
+
+    let marker = 0u8;
+    assert!(guard.start_address() < VirtAddr::from_ptr(&marker));
+    assert!(memory::is_stack_guard(guard.start_address() + 8u64));
+    assert_eq!(memory::translate_addr(mapper, guard.start_address()), None);
+}
+
+#[test_case]
+fn test_map_mmio_vga_buffer() {
+    let (mapper, frame_allocator) = &mut *MEMORY.get().expect("memory not initialized").lock();
+    let size = 80 * 25 * 2;
+
+    // SAFETY: The VGA buffer is device memory, aliasing it is fine.
+    let ptr = unsafe { memory::map_mmio(mapper, frame_allocator, PhysAddr::new(0xb8000), size) }
+        .expect("map_mmio failed");
+    assert_eq!(
+        memory::translate_addr(mapper, VirtAddr::from_ptr(ptr)),
+        Some(PhysAddr::new(0xb8000))
+    );
+
+    // The last cell, written through the new mapping, shows up in the
+    // identity-mapped buffer
+    let last = size - 2;
+    // SAFETY: ptr maps size bytes of the VGA buffer, which is also identity
+    // mapped at 0xb8000.
+    unsafe {
+        ptr.add(last).write_volatile(b'M');
+        assert_eq!((0xb8000 as *const u8).add(last).read_volatile(), b'M');
+    }
+
+    // SAFETY: ptr and size come from map_mmio and ptr is not used again.
+    unsafe { memory::unmap_mmio(mapper, ptr, size) }.expect("unmap_mmio failed");
+    assert_eq!(
+        memory::translate_addr(mapper, VirtAddr::from_ptr(ptr)),
+        None
+    );
+}
@@ -1,24 +1,27 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kleinos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use bootloader::entry_point;
 use core::panic::PanicInfo;
-use kleinos::{
-    qemu::{QemuExitCode, qemu_exit},
-    serial_print, serial_println,
-};
+use kleinos::{expect_panic, idle_loop, serial};
 
-entry_point!(should_fail);
+entry_point!(test_kernel_main);
 
-fn should_fail(_boot_info: &'static bootloader::BootInfo) -> ! {
-    serial_print!("should_fail::should_fail...\t");
-    assert_eq!(0, 1);
-    serial_println!("[test did not panic]");
-    qemu_exit(QemuExitCode::Failure);
+fn test_kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
+    serial::init();
+    test_main();
+    idle_loop();
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    qemu_exit(QemuExitCode::Success);
+fn panic(info: &PanicInfo) -> ! {
+    kleinos::test_panic_handler(info);
+}
+
+#[test_case]
+fn should_fail() {
+    expect_panic(|| assert_eq!(0, 1));
 }